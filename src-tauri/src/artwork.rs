@@ -0,0 +1,361 @@
+use crate::cmd::AppArg;
+use crate::error::{Error, ErrorKind};
+use crate::files::FileError;
+use crate::frames::Metadata;
+use crate::throw;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::PathBuf;
+use tauri::command;
+
+#[derive(Serialize)]
+pub struct ImageInfo {
+	width: Option<u32>,
+	height: Option<u32>,
+	size: usize,
+}
+
+#[derive(Serialize)]
+pub struct ImageFileInfo {
+	#[serde(flatten)]
+	info: ImageInfo,
+	mime_type: String,
+	/// Whether the current file can store this format without conversion
+	supported: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct ProcessOptions {
+	/// Downscale so neither side is longer than this
+	pub max_edge: Option<u32>,
+	/// Re-encode as JPEG with this quality, from 1 to 100
+	pub jpeg_quality: Option<u8>,
+	/// Crop the center square
+	#[serde(default)]
+	pub square_crop: bool,
+}
+
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+pub fn get_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+	let reader = image::io::Reader::new(Cursor::new(data))
+		.with_guessed_format()
+		.ok()?;
+	reader.into_dimensions().ok()
+}
+
+/// Detect the image format from its content
+pub fn detect_mime_type(data: &[u8]) -> Result<&'static str, Error> {
+	let mime_type = match image::guess_format(data) {
+		Ok(ImageFormat::Jpeg) => "image/jpeg",
+		Ok(ImageFormat::Png) => "image/png",
+		Ok(ImageFormat::Bmp) => "image/bmp",
+		Ok(ImageFormat::Gif) => "image/gif",
+		Ok(ImageFormat::Tiff) => "image/tiff",
+		Ok(ImageFormat::WebP) => "image/webp",
+		Ok(format) => throw!(UnsupportedFormat: "Unsupported image format: {:?}", format),
+		Err(_) => throw!(UnsupportedFormat: "Unrecognized image format"),
+	};
+	Ok(mime_type)
+}
+
+/// Whether a tag can store images of this MIME type. MP4 only knows JPEG, PNG
+/// and BMP, and FLAC/Ogg pictures are limited to what readers understand.
+pub fn supports(metadata: &Metadata, mime_type: &str) -> bool {
+	match metadata {
+		Metadata::Id3(_) => true,
+		Metadata::Mp4(_) => matches!(mime_type, "image/jpeg" | "image/png" | "image/bmp"),
+		Metadata::VorbisComments(_) => matches!(
+			mime_type,
+			"image/jpeg" | "image/png" | "image/bmp" | "image/gif" | "image/tiff"
+		),
+	}
+}
+
+/// Convert an image to JPEG or PNG
+pub fn convert(data: &[u8], to_mime_type: &str) -> Result<Vec<u8>, Error> {
+	let img = match image::load_from_memory(data) {
+		Ok(img) => img,
+		Err(e) => throw!(Parse: "Error reading image: {}", e),
+	};
+	match to_mime_type {
+		"image/jpeg" => encode_jpeg(&img, DEFAULT_JPEG_QUALITY),
+		"image/png" => {
+			let mut bytes = Vec::new();
+			match img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png) {
+				Ok(_) => Ok(bytes),
+				Err(e) => throw!(UnsupportedFormat: "Error encoding PNG: {}", e),
+			}
+		}
+		mime_type => throw!(UnsupportedFormat: "Can't convert images to {}", mime_type),
+	}
+}
+
+/// Average hash: one bit per pixel of an 8x8 grayscale thumbnail, set if the
+/// pixel is brighter than the average. Similar images have similar hashes.
+pub fn perceptual_hash(data: &[u8]) -> Option<u64> {
+	let img = image::load_from_memory(data).ok()?;
+	let pixels = img.resize_exact(8, 8, FilterType::Triangle).to_luma8();
+	let average = pixels.iter().map(|p| *p as u32).sum::<u32>() / 64;
+	let mut hash = 0;
+	for (i, pixel) in pixels.iter().enumerate() {
+		if *pixel as u32 > average {
+			hash |= 1 << i;
+		}
+	}
+	Some(hash)
+}
+
+/// Whether two perceptual hashes are close enough to be the same picture,
+/// for example a cover saved at a different size or quality
+pub fn looks_identical(a: Option<u64>, b: Option<u64>) -> bool {
+	match (a, b) {
+		(Some(a), Some(b)) => (a ^ b).count_ones() <= 4,
+		_ => false,
+	}
+}
+
+pub fn hash_bytes(data: &[u8]) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	data.hash(&mut hasher);
+	hasher.finish()
+}
+
+/// Downscale an image to fit in a `size` by `size` square, as a JPEG.
+/// Smaller images keep their size.
+pub fn thumbnail(data: &[u8], size: u32) -> Result<Vec<u8>, Error> {
+	let img = match image::load_from_memory(data) {
+		Ok(img) => img,
+		Err(e) => throw!(Parse: "Error reading image: {}", e),
+	};
+	if img.width() <= size && img.height() <= size {
+		return encode_jpeg(&img, THUMBNAIL_JPEG_QUALITY);
+	}
+	encode_jpeg(&img.thumbnail(size, size), THUMBNAIL_JPEG_QUALITY)
+}
+
+pub fn get_info(data: &[u8]) -> ImageInfo {
+	let dimensions = get_dimensions(data);
+	ImageInfo {
+		width: dimensions.map(|(w, _)| w),
+		height: dimensions.map(|(_, h)| h),
+		size: data.len(),
+	}
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, Error> {
+	let mut bytes = Vec::new();
+	let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, quality);
+	// JPEG has no alpha channel
+	match encoder.encode_image(&DynamicImage::ImageRgb8(img.to_rgb8())) {
+		Ok(_) => Ok(bytes),
+		Err(e) => throw!(UnsupportedFormat: "Error encoding JPEG: {}", e),
+	}
+}
+
+/// Crop, downscale and re-encode an image. Returns the new JPEG bytes, or None
+/// if the image doesn't need to change.
+pub fn process(data: &[u8], options: &ProcessOptions) -> Result<Option<Vec<u8>>, Error> {
+	let mut img = match image::load_from_memory(data) {
+		Ok(img) => img,
+		Err(e) => throw!(Parse: "Error reading image: {}", e),
+	};
+	let mut changed = false;
+	let (width, height) = img.dimensions();
+	if options.square_crop && width != height {
+		let side = width.min(height);
+		img = img.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+		changed = true;
+	}
+	if let Some(max_edge) = options.max_edge {
+		let (width, height) = img.dimensions();
+		if width > max_edge || height > max_edge {
+			img = img.resize(max_edge, max_edge, FilterType::Lanczos3);
+			changed = true;
+		}
+	}
+	let quality = match options.jpeg_quality {
+		Some(quality) => quality.clamp(1, 100),
+		None if changed => DEFAULT_JPEG_QUALITY,
+		None => return Ok(None),
+	};
+	let bytes = encode_jpeg(&img, quality)?;
+	// re-encoding alone shouldn't make the image bigger
+	if !changed && bytes.len() >= data.len() {
+		return Ok(None);
+	}
+	Ok(Some(bytes))
+}
+
+/// The data of every picture, in tag order
+fn picture_data(metadata: &Metadata) -> Vec<Vec<u8>> {
+	match metadata {
+		Metadata::Id3(tag) => tag.pictures().map(|pic| pic.data.clone()).collect(),
+		Metadata::Mp4(tag) => tag
+			.artworks()
+			.map(|artwork| artwork.data.to_vec())
+			.collect(),
+		Metadata::VorbisComments(tag) => tag
+			.pictures()
+			.iter()
+			.map(|(pic, _info)| pic.data().to_vec())
+			.collect(),
+	}
+}
+
+/// Replace the pictures that have new JPEG bytes in `replacements`, which
+/// has one entry per picture. Picture types and descriptions are kept. The
+/// tag is only changed if every picture can be replaced. Returns the number
+/// of pictures replaced.
+fn replace_picture_data(
+	metadata: &mut Metadata,
+	replacements: Vec<Option<Vec<u8>>>,
+) -> Result<usize, Error> {
+	let count = replacements.iter().filter(|data| data.is_some()).count();
+	match metadata {
+		Metadata::Id3(tag) => {
+			let mut pic_frames: Vec<_> = tag
+				.frames()
+				.filter(|frame| frame.content().picture().is_some())
+				.map(|frame| frame.clone())
+				.collect();
+			for (pic_frame, data) in pic_frames.iter_mut().zip(replacements) {
+				let data = match data {
+					Some(data) => data,
+					None => continue,
+				};
+				let pic = pic_frame.content().picture().unwrap();
+				let new_pic = id3::frame::Picture {
+					mime_type: "image/jpeg".to_string(),
+					picture_type: pic.picture_type,
+					description: pic.description.clone(),
+					data,
+				};
+				*pic_frame = id3::Frame::with_content("APIC", id3::Content::Picture(new_pic));
+			}
+			crate::image::set_id3_pictures(tag, pic_frames);
+		}
+		Metadata::Mp4(tag) => {
+			let mut artworks: Vec<_> = tag.take_artworks().collect();
+			for (artwork, data) in artworks.iter_mut().zip(replacements) {
+				if let Some(data) = data {
+					*artwork = mp4ameta::Img {
+						fmt: mp4ameta::ImgFmt::Jpeg,
+						data,
+					};
+				}
+			}
+			tag.set_artworks(artworks);
+		}
+		Metadata::VorbisComments(tag) => {
+			let mut new_pictures = Vec::new();
+			for (i, ((pic, _info), data)) in tag.pictures().iter().zip(replacements).enumerate() {
+				let data = match data {
+					Some(data) => data,
+					None => continue,
+				};
+				let new_pic = lofty::Picture::new_unchecked(
+					pic.pic_type(),
+					lofty::MimeType::Jpeg,
+					pic.description().map(|s| s.to_string()),
+					data,
+				);
+				let info = match lofty::PictureInformation::from_picture(&new_pic) {
+					Ok(info) => info,
+					Err(e) => throw!(Parse: "Error reading picture info: {}", e),
+				};
+				new_pictures.push((i, new_pic, info));
+			}
+			for (i, new_pic, info) in new_pictures {
+				tag.set_picture(i, new_pic, info);
+			}
+		}
+	}
+	Ok(count)
+}
+
+/// Get the dimensions, size and format of an image file, so the user can
+/// decide whether to process or convert it before importing it
+#[command]
+pub fn get_image_file_info(path: PathBuf, app: AppArg<'_>) -> Result<ImageFileInfo, Error> {
+	let bytes = match fs::read(&path) {
+		Ok(bytes) => bytes,
+		Err(e) => return Err(Error::io("Error reading that file", &path, e)),
+	};
+	let mime_type = detect_mime_type(&bytes)?;
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	Ok(ImageFileInfo {
+		info: get_info(&bytes),
+		mime_type: mime_type.to_string(),
+		supported: supports(&file.metadata, mime_type),
+	})
+}
+
+#[derive(Default, Serialize)]
+pub struct NormalizeReport {
+	/// Number of pictures that were changed
+	changed: usize,
+	failed: Vec<FileError>,
+}
+
+/// Process the artwork of every open file. A file is only changed if all of
+/// its pictures could be processed. The app is only locked while reading and
+/// writing the pictures of a file, not while processing them.
+#[command]
+pub async fn normalize_all_artwork(
+	options: ProcessOptions,
+	app: AppArg<'_>,
+) -> Result<NormalizeReport, Error> {
+	let mut report = NormalizeReport::default();
+	let file_count = app.lock()?.files.len();
+	for index in 0..file_count {
+		let (path, pictures) = match app.lock()?.files.get(index) {
+			Some(file) => (file.path.clone(), picture_data(&file.metadata)),
+			None => break,
+		};
+		let replacements: Result<Vec<_>, Error> = pictures
+			.iter()
+			.map(|data| process(data, &options))
+			.collect();
+		let replacements = match replacements {
+			Ok(replacements) => replacements,
+			Err(e) => {
+				let e = e.context("Error processing artwork");
+				report.failed.push(FileError::new(path, e));
+				continue;
+			}
+		};
+		if replacements.iter().all(|data| data.is_none()) {
+			continue;
+		}
+		let mut app = app.lock()?;
+		let file = match app.files.get_mut(index) {
+			Some(file) if file.path == path => file,
+			// closed while processing
+			_ => continue,
+		};
+		if picture_data(&file.metadata) != pictures {
+			let e = Error::new(
+				ErrorKind::Conflict,
+				"The artwork was changed while it was being processed",
+			);
+			report.failed.push(FileError::new(path, e));
+			continue;
+		}
+		match replace_picture_data(&mut file.metadata, replacements) {
+			Ok(count) => {
+				file.dirty = true;
+				report.changed += count;
+			}
+			Err(e) => report.failed.push(FileError::new(path, e)),
+		}
+	}
+	Ok(report)
+}
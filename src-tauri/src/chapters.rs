@@ -0,0 +1,629 @@
+use crate::artwork;
+use crate::cmd::{AppArg, File};
+use crate::error::Error;
+use crate::frames::Metadata;
+use crate::throw;
+use base64::{self, Engine};
+use id3::TagLike;
+use lofty::AudioFile;
+use serde::Serialize;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::command;
+
+#[derive(Clone, Serialize)]
+pub struct Chapter {
+	pub start_ms: u32,
+	pub end_ms: u32,
+	pub title: String,
+	pub has_image: bool,
+}
+
+#[derive(Serialize)]
+pub struct ChapterImage {
+	data: String,
+	mime_type: String,
+}
+
+/// Offsets are set to this when chapters use times instead of byte offsets
+const NO_OFFSET: u32 = 0xFFFFFFFF;
+
+fn chapter_title(chapter: &id3::frame::Chapter) -> String {
+	for frame in &chapter.frames {
+		if frame.id() == "TIT2" {
+			if let Some(title) = frame.content().text() {
+				return title.to_string();
+			}
+		}
+	}
+	"".to_string()
+}
+
+fn chapter_picture(chapter: &id3::frame::Chapter) -> Option<&id3::frame::Picture> {
+	chapter
+		.frames
+		.iter()
+		.find_map(|frame| frame.content().picture())
+}
+
+/// Get CHAP frames in the order of the top-level CTOC frame, followed by any
+/// chapters it doesn't reference, sorted by start time
+fn read_chapters(tag: &id3::Tag) -> Vec<id3::frame::Chapter> {
+	let mut chapters: Vec<_> = tag
+		.frames()
+		.filter_map(|frame| frame.content().chapter())
+		.cloned()
+		.collect();
+	chapters.sort_by_key(|chapter| chapter.start_time);
+	let toc = tag
+		.frames()
+		.filter_map(|frame| frame.content().table_of_contents())
+		.find(|toc| toc.top_level);
+	let mut ordered = Vec::new();
+	if let Some(toc) = toc {
+		for element_id in &toc.elements {
+			if let Some(i) = chapters.iter().position(|c| &c.element_id == element_id) {
+				ordered.push(chapters.remove(i));
+			}
+		}
+	}
+	ordered.append(&mut chapters);
+	ordered
+}
+
+/// Replace all CHAP frames and rebuild the top-level CTOC frame to reference
+/// them in order. Frames of the old CTOC, like its title, are kept.
+fn write_chapters(tag: &mut id3::Tag, chapters: Vec<id3::frame::Chapter>) {
+	let old_toc = tag
+		.frames()
+		.filter_map(|frame| frame.content().table_of_contents())
+		.find(|toc| toc.top_level)
+		.cloned();
+	let other_tocs: Vec<_> = tag
+		.frames()
+		.filter(|frame| match frame.content().table_of_contents() {
+			Some(toc) => !toc.top_level,
+			None => false,
+		})
+		.cloned()
+		.collect();
+	tag.remove("CHAP");
+	tag.remove("CTOC");
+	if chapters.is_empty() {
+		return;
+	}
+	let toc = id3::frame::TableOfContents {
+		element_id: match old_toc {
+			Some(ref toc) => toc.element_id.clone(),
+			None => "toc".to_string(),
+		},
+		top_level: true,
+		ordered: true,
+		elements: chapters.iter().map(|c| c.element_id.clone()).collect(),
+		frames: old_toc.map(|toc| toc.frames).unwrap_or_default(),
+	};
+	tag.add_frame(id3::Frame::with_content(
+		"CTOC",
+		id3::Content::TableOfContents(toc),
+	));
+	for toc_frame in other_tocs {
+		tag.add_frame(toc_frame);
+	}
+	for chapter in chapters {
+		tag.add_frame(id3::Frame::with_content(
+			"CHAP",
+			id3::Content::Chapter(chapter),
+		));
+	}
+}
+
+fn new_element_id(chapters: &[id3::frame::Chapter]) -> String {
+	let mut n = chapters.len();
+	loop {
+		let id = format!("chp{}", n);
+		if !chapters.iter().any(|c| c.element_id == id) {
+			return id;
+		}
+		n += 1;
+	}
+}
+
+fn id3_tag(metadata: &mut Metadata) -> Result<&mut id3::Tag, Error> {
+	match metadata {
+		Metadata::Id3(tag) => Ok(tag),
+		_ => throw!(UnsupportedFormat: "Chapter images are only supported for ID3 tags"),
+	}
+}
+
+/// MP4 chapters only have a start time, so each one ends where the next one
+/// starts. Nero chapter lists are read first, falling back to the QuickTime
+/// chapter track.
+fn read_mp4_chapters(tag: &mp4ameta::Tag, duration_ms: Option<u32>) -> Vec<Chapter> {
+	let mut list = tag.chapter_list();
+	if list.is_empty() {
+		list = tag.chapter_track();
+	}
+	let mut starts: Vec<_> = list
+		.iter()
+		.map(|c| (c.start.as_millis() as u32, c.title.clone()))
+		.collect();
+	starts.sort_by_key(|(start, _)| *start);
+	to_mp4_page_chapters(&starts, duration_ms)
+}
+
+fn to_mp4_page_chapters(starts: &[(u32, String)], duration_ms: Option<u32>) -> Vec<Chapter> {
+	let mut chapters = Vec::new();
+	for (i, (start_ms, title)) in starts.iter().enumerate() {
+		let end_ms = match starts.get(i + 1) {
+			Some((next_start, _)) => *next_start,
+			None => duration_ms.unwrap_or(*start_ms).max(*start_ms),
+		};
+		chapters.push(Chapter {
+			start_ms: *start_ms,
+			end_ms,
+			title: title.clone(),
+			has_image: false,
+		});
+	}
+	chapters
+}
+
+/// Write both the Nero chapter list and the QuickTime chapter track, since
+/// players tend to only support one of them
+fn write_mp4_chapters(tag: &mut mp4ameta::Tag, mut starts: Vec<(u32, String)>) {
+	starts.sort_by_key(|(start, _)| *start);
+	let chapters: Vec<_> = starts
+		.into_iter()
+		.map(|(start_ms, title)| mp4ameta::Chapter {
+			start: Duration::from_millis(start_ms as u64),
+			title,
+		})
+		.collect();
+	tag.set_chapter_list(chapters.clone());
+	tag.set_chapter_track(chapters);
+}
+
+fn mp4_starts(chapters: &[Chapter]) -> Vec<(u32, String)> {
+	chapters
+		.iter()
+		.map(|c| (c.start_ms, c.title.clone()))
+		.collect()
+}
+
+pub fn get_chapters_of(file: &File) -> Vec<Chapter> {
+	match file.metadata {
+		Metadata::Id3(ref tag) => to_page_chapters(tag),
+		Metadata::Mp4(ref tag) => {
+			if tag.chapter_list().is_empty() && tag.chapter_track().is_empty() {
+				return Vec::new();
+			}
+			read_mp4_chapters(tag, file.duration_ms)
+		}
+		Metadata::VorbisComments(_) => Vec::new(),
+	}
+}
+
+pub fn get_duration_ms(path: &Path) -> Option<u32> {
+	let file = lofty::read_from_path(path).ok()?;
+	Some(file.properties().duration().as_millis() as u32)
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+	let (time, frac) = match s.split_once('.') {
+		Some((time, frac)) => (time, frac),
+		None => (s, ""),
+	};
+	let mut secs: u32 = 0;
+	for part in time.split(':') {
+		secs = secs
+			.checked_mul(60)?
+			.checked_add(part.parse::<u32>().ok()?)?;
+	}
+	let mut ms = secs.checked_mul(1000)?;
+	if !frac.is_empty() {
+		let frac = format!("{:0<3}", frac);
+		ms = ms.checked_add(frac.get(..3)?.parse::<u32>().ok()?)?;
+	}
+	Some(ms)
+}
+
+fn format_time(ms: u32) -> String {
+	let secs = ms / 1000;
+	let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+	match ms % 1000 {
+		0 => time,
+		frac => format!("{}.{:03}", time, frac),
+	}
+}
+
+/// Parse chapter start times and titles from "HH:MM:SS Title" lines
+fn parse_text(text: &str) -> Vec<(u32, String)> {
+	let mut starts = Vec::new();
+	for line in text.lines() {
+		let line = line.trim();
+		let (time, title) = match line.split_once(char::is_whitespace) {
+			Some((time, title)) => (time, title.trim()),
+			None => (line, ""),
+		};
+		if let Some(ms) = parse_time(time) {
+			starts.push((ms, title.to_string()));
+		}
+	}
+	starts
+}
+
+fn unquote(s: &str) -> String {
+	s.trim().trim_matches('"').to_string()
+}
+
+/// CUE times are MM:SS:FF with 75 frames per second. None if the time is too
+/// large.
+fn cue_time_ms(min: u32, sec: u32, frames: u32) -> Option<u32> {
+	let secs = min.checked_mul(60)?.checked_add(sec)?;
+	secs.checked_mul(1000)?
+		.checked_add(frames.checked_mul(1000)? / 75)
+}
+
+/// Parse chapter start times and titles from the tracks of a CUE sheet
+fn parse_cue(text: &str) -> Vec<(u32, String)> {
+	let mut starts = Vec::new();
+	let mut in_track = false;
+	let mut title = String::new();
+	for line in text.lines() {
+		let line = line.trim();
+		if line.starts_with("TRACK ") {
+			in_track = true;
+			title = String::new();
+		} else if let Some(value) = line.strip_prefix("TITLE ") {
+			if in_track {
+				title = unquote(value);
+			}
+		} else if let Some(value) = line.strip_prefix("INDEX 01 ") {
+			let parts: Vec<_> = value.trim().split(':').collect();
+			if let [min, sec, frames] = parts[..] {
+				let min: u32 = min.parse().unwrap_or(0);
+				let sec: u32 = sec.parse().unwrap_or(0);
+				let frames: u32 = frames.parse().unwrap_or(0);
+				if let Some(ms) = cue_time_ms(min, sec, frames) {
+					starts.push((ms, title.clone()));
+				}
+			}
+		}
+	}
+	starts
+}
+
+fn to_text(chapters: &[Chapter]) -> String {
+	let mut text = String::new();
+	for chapter in chapters {
+		text.push_str(&format!(
+			"{} {}\n",
+			format_time(chapter.start_ms),
+			chapter.title
+		));
+	}
+	text
+}
+
+/// The CUE FILE type of an audio file. CUE has no type for formats other than
+/// MP3, AIFF and WAVE, and players read WAVE as any format they can decode.
+fn cue_file_type(path: &Path) -> &'static str {
+	let mut header = [0; 12];
+	let read = fs::File::open(path).and_then(|mut file| file.read_exact(&mut header));
+	if read.is_err() {
+		return "WAVE";
+	}
+	if &header[0..4] == b"FORM" && matches!(&header[8..12], b"AIFF" | b"AIFC") {
+		"AIFF"
+	} else if &header[0..3] == b"ID3" || (header[0] == 0xFF && header[1] & 0xE0 == 0xE0) {
+		"MP3"
+	} else {
+		"WAVE"
+	}
+}
+
+fn to_cue(chapters: &[Chapter], audio_path: &Path) -> String {
+	let file_name = audio_path.file_name().unwrap_or_default().to_string_lossy();
+	let file_type = cue_file_type(audio_path);
+	let mut cue = format!("FILE \"{}\" {}\n", file_name, file_type);
+	for (i, chapter) in chapters.iter().enumerate() {
+		let secs = chapter.start_ms / 1000;
+		let frames = chapter.start_ms % 1000 * 75 / 1000;
+		cue.push_str(&format!("  TRACK {:02} AUDIO\n", i + 1));
+		cue.push_str(&format!(
+			"    TITLE \"{}\"\n",
+			chapter.title.replace('"', "'")
+		));
+		cue.push_str(&format!(
+			"    INDEX 01 {:02}:{:02}:{:02}\n",
+			secs / 60,
+			secs % 60,
+			frames
+		));
+	}
+	cue
+}
+
+fn to_page_chapters(tag: &id3::Tag) -> Vec<Chapter> {
+	read_chapters(tag)
+		.iter()
+		.map(|chapter| Chapter {
+			start_ms: chapter.start_time,
+			end_ms: chapter.end_time,
+			title: chapter_title(chapter),
+			has_image: chapter_picture(chapter).is_some(),
+		})
+		.collect()
+}
+
+#[command]
+pub fn get_chapters(app: AppArg<'_>) -> Result<Vec<Chapter>, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	Ok(get_chapters_of(file))
+}
+
+#[command]
+pub fn get_chapter_image(index: usize, app: AppArg<'_>) -> Result<Option<ChapterImage>, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let tag = id3_tag(&mut file.metadata)?;
+	let chapters = read_chapters(tag);
+	let chapter = match chapters.get(index) {
+		Some(chapter) => chapter,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	Ok(chapter_picture(chapter).map(|pic| ChapterImage {
+		data: base64::engine::general_purpose::STANDARD.encode(&pic.data),
+		mime_type: pic.mime_type.clone(),
+	}))
+}
+
+/// Set the time and title of the chapter at `index`, or add a new chapter if
+/// `index` is the number of chapters. Returns the end time that was stored,
+/// which in MP4 is always where the next chapter starts, or the end of the
+/// file, so `end_ms` is ignored.
+#[command]
+pub fn set_chapter(
+	index: usize,
+	start_ms: u32,
+	end_ms: u32,
+	title: String,
+	app: AppArg<'_>,
+) -> Result<u32, Error> {
+	if end_ms < start_ms {
+		throw!(InvalidInput: "Chapter ends before it starts");
+	}
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let duration_ms = file.duration_ms;
+	let tag = match file.metadata {
+		Metadata::Id3(ref mut tag) => tag,
+		Metadata::Mp4(ref mut tag) => {
+			let mut starts = mp4_starts(&read_mp4_chapters(tag, duration_ms));
+			match starts.get_mut(index) {
+				Some(chapter) => *chapter = (start_ms, title),
+				None => {
+					if index == starts.len() {
+						starts.push((start_ms, title));
+					} else {
+						throw!(IndexOutOfRange: "Index out of range");
+					}
+				}
+			}
+			write_mp4_chapters(tag, starts);
+			file.dirty = true;
+			let stored_end = read_mp4_chapters(tag, duration_ms)
+				.iter()
+				.find(|chapter| chapter.start_ms == start_ms)
+				.map_or(start_ms, |chapter| chapter.end_ms);
+			return Ok(stored_end);
+		}
+		Metadata::VorbisComments(_) => {
+			throw!(UnsupportedFormat: "Chapters are not supported for this file type")
+		}
+	};
+	let mut chapters = read_chapters(tag);
+	if index == chapters.len() {
+		chapters.push(id3::frame::Chapter {
+			element_id: new_element_id(&chapters),
+			start_time: start_ms,
+			end_time: end_ms,
+			start_offset: NO_OFFSET,
+			end_offset: NO_OFFSET,
+			frames: Vec::new(),
+		});
+	}
+	let chapter = match chapters.get_mut(index) {
+		Some(chapter) => chapter,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	chapter.start_time = start_ms;
+	chapter.end_time = end_ms;
+	chapter.frames.retain(|frame| frame.id() != "TIT2");
+	if !title.is_empty() {
+		chapter.frames.insert(
+			0,
+			id3::Frame::with_content("TIT2", id3::Content::Text(title)),
+		);
+	}
+	write_chapters(tag, chapters);
+	file.dirty = true;
+	Ok(end_ms)
+}
+
+#[command]
+pub fn move_chapter(from: usize, to: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let tag = match file.metadata {
+		Metadata::Id3(ref mut tag) => tag,
+		Metadata::Mp4(_) => {
+			throw!(UnsupportedFormat: "MP4 chapters are always ordered by start time")
+		}
+		Metadata::VorbisComments(_) => {
+			throw!(UnsupportedFormat: "Chapters are not supported for this file type")
+		}
+	};
+	let mut chapters = read_chapters(tag);
+	if from >= chapters.len() || to >= chapters.len() {
+		throw!(IndexOutOfRange: "Index out of range");
+	}
+	let chapter = chapters.remove(from);
+	chapters.insert(to, chapter);
+	write_chapters(tag, chapters);
+	file.dirty = true;
+	Ok(())
+}
+
+#[command]
+pub fn remove_chapter(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let duration_ms = file.duration_ms;
+	match file.metadata {
+		Metadata::Id3(ref mut tag) => {
+			let mut chapters = read_chapters(tag);
+			if index >= chapters.len() {
+				throw!(IndexOutOfRange: "Index out of range");
+			}
+			chapters.remove(index);
+			write_chapters(tag, chapters);
+		}
+		Metadata::Mp4(ref mut tag) => {
+			let mut starts = mp4_starts(&read_mp4_chapters(tag, duration_ms));
+			if index >= starts.len() {
+				throw!(IndexOutOfRange: "Index out of range");
+			}
+			starts.remove(index);
+			write_mp4_chapters(tag, starts);
+		}
+		Metadata::VorbisComments(_) => {
+			throw!(UnsupportedFormat: "Chapters are not supported for this file type")
+		}
+	}
+	file.dirty = true;
+	Ok(())
+}
+
+/// Set the image of the chapter at `index`, or remove it if `path` is None
+#[command]
+pub fn set_chapter_image(
+	index: usize,
+	path: Option<PathBuf>,
+	app: AppArg<'_>,
+) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let tag = id3_tag(&mut file.metadata)?;
+	let mut chapters = read_chapters(tag);
+	let chapter = match chapters.get_mut(index) {
+		Some(chapter) => chapter,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	chapter
+		.frames
+		.retain(|frame| frame.content().picture().is_none());
+	if let Some(path) = path {
+		let data = match fs::read(&path) {
+			Ok(b) => b,
+			Err(e) => return Err(Error::io("Error reading that file", &path, e)),
+		};
+		let mime_type = artwork::detect_mime_type(&data)?;
+		let pic = id3::frame::Picture {
+			mime_type: mime_type.to_string(),
+			picture_type: id3::frame::PictureType::Other,
+			description: "".to_string(),
+			data,
+		};
+		chapter
+			.frames
+			.push(id3::Frame::with_content("APIC", id3::Content::Picture(pic)));
+	}
+	write_chapters(tag, chapters);
+	file.dirty = true;
+	Ok(())
+}
+
+/// Replace all chapters with ones from a CUE sheet or a text file with
+/// "HH:MM:SS Title" lines. Each chapter ends where the next one starts, and
+/// the last one ends at the end of the audio.
+#[command]
+pub fn import_chapters(path: PathBuf, app: AppArg<'_>) -> Result<(), Error> {
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(e) => return Err(Error::io("Error reading that file", &path, e)),
+	};
+	let ext = path.extension().unwrap_or_default().to_string_lossy();
+	let mut starts = match ext.as_ref() {
+		"cue" => parse_cue(&text),
+		_ => parse_text(&text),
+	};
+	if starts.is_empty() {
+		throw!(Parse: "No chapters found in {}", path.to_string_lossy());
+	}
+	starts.sort_by_key(|(start, _)| *start);
+
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let duration_ms = file.duration_ms;
+	let tag = match file.metadata {
+		Metadata::Id3(ref mut tag) => tag,
+		Metadata::Mp4(ref mut tag) => {
+			write_mp4_chapters(tag, starts);
+			file.dirty = true;
+			return Ok(());
+		}
+		Metadata::VorbisComments(_) => {
+			throw!(UnsupportedFormat: "Chapters are not supported for this file type")
+		}
+	};
+	let mut chapters = Vec::new();
+	for (i, (start_ms, title)) in starts.iter().enumerate() {
+		let end_ms = match starts.get(i + 1) {
+			Some((next_start, _)) => *next_start,
+			None => duration_ms.unwrap_or(*start_ms).max(*start_ms),
+		};
+		let mut frames = Vec::new();
+		if !title.is_empty() {
+			frames.push(id3::Frame::with_content(
+				"TIT2",
+				id3::Content::Text(title.clone()),
+			));
+		}
+		chapters.push(id3::frame::Chapter {
+			element_id: format!("chp{}", i),
+			start_time: *start_ms,
+			end_time: end_ms,
+			start_offset: NO_OFFSET,
+			end_offset: NO_OFFSET,
+			frames,
+		});
+	}
+	write_chapters(tag, chapters);
+	file.dirty = true;
+	Ok(())
+}
+
+/// Export chapters as a CUE sheet if `path` ends with .cue, otherwise as
+/// "HH:MM:SS Title" lines
+#[command]
+pub fn export_chapters(path: PathBuf, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let chapters = get_chapters_of(file);
+	if chapters.is_empty() {
+		throw!(InvalidInput: "This file has no chapters");
+	}
+	let ext = path.extension().unwrap_or_default().to_string_lossy();
+	let text = match ext.as_ref() {
+		"cue" => to_cue(&chapters, &file.path),
+		_ => to_text(&chapters),
+	};
+	match fs::write(&path, text) {
+		Ok(_) => Ok(()),
+		Err(e) => return Err(Error::io("Error writing file", &path, e)),
+	}
+}
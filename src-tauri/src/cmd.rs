@@ -1,11 +1,19 @@
+use crate::chapters;
+use crate::comments;
+use crate::error::{self, Error};
+use crate::fields;
 use crate::frames::Metadata;
-use crate::throw;
+use crate::rating;
+use crate::save::SaveOptions;
+use crate::spreadsheet;
+use crate::watch::Fingerprint;
 use id3::TagLike;
 use lofty::Accessor;
 use serde::Serialize;
 use serde_json::Value;
+use std::fs::FileTimes;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use tauri::api::dialog;
 use tauri::{command, State};
@@ -16,26 +24,67 @@ pub struct File {
 	pub dirty: bool,
 	#[serde(skip_serializing)]
 	pub metadata: Metadata,
+	/// The tag as it was when the file was opened or last saved
+	#[serde(skip_serializing)]
+	pub saved_metadata: Metadata,
+	#[serde(skip_serializing)]
+	pub disk: Option<Fingerprint>,
+	/// Access and modification times from when the file was opened or last
+	/// saved, restored on save if `preserve_times` is set
+	#[serde(skip_serializing)]
+	pub times: Option<FileTimes>,
+	/// Length of the audio, read when the file is opened since MP4 chapter
+	/// ends depend on it. None for formats without chapters.
+	#[serde(skip_serializing)]
+	pub duration_ms: Option<u32>,
+	/// Whether another program changed the file since it was opened or saved
+	pub changed_on_disk: bool,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Serialize)]
 pub struct App {
 	pub current_index: usize,
 	pub files: Vec<File>,
+	pub popm_email: String,
+	pub save_options: SaveOptions,
+	#[serde(skip_serializing)]
+	pub clipboard: Vec<fields::CopiedField>,
+	/// Changes from a spreadsheet that were previewed but not applied yet
+	#[serde(skip_serializing)]
+	pub tag_import: Option<spreadsheet::TagImport>,
+}
+impl Default for App {
+	fn default() -> Self {
+		App {
+			current_index: 0,
+			files: Vec::new(),
+			popm_email: rating::default_popm_email(),
+			save_options: SaveOptions::default(),
+			clipboard: Vec::new(),
+			tag_import: None,
+		}
+	}
 }
 impl App {
-	pub fn current_file(&mut self) -> Result<&mut File, String> {
-		match self.files.get_mut(self.current_index) {
+	pub fn current_file(&mut self) -> Result<&mut File, Error> {
+		self.file_mut(self.current_index)
+	}
+	pub fn file_mut(&mut self, index: usize) -> Result<&mut File, Error> {
+		let len = self.files.len();
+		match self.files.get_mut(index) {
 			Some(file) => Ok(file),
-			None => {
-				throw!("Error getting open file")
-			}
+			None => Err(Error::index(index, len)),
 		}
 	}
 }
 
 #[derive(Default)]
 pub struct AppState(pub Arc<Mutex<App>>);
+impl AppState {
+	pub fn lock(&self) -> Result<MutexGuard<'_, App>, Error> {
+		error::lock(&self.0)
+	}
+}
 
 pub type AppArg<'a> = State<'a, AppState>;
 
@@ -48,15 +97,22 @@ pub fn error_popup(msg: String, win: tauri::Window) {
 }
 
 #[command]
-pub fn get_app(app: AppArg<'_>) -> Value {
-	let app = app.0.lock().unwrap();
-	serde_json::to_value(&*app).unwrap()
+pub fn get_app(app: AppArg<'_>) -> Result<Value, Error> {
+	let app = app.lock()?;
+	match serde_json::to_value(&*app) {
+		Ok(value) => Ok(value),
+		Err(e) => Err(format!("Error serializing app: {}", e).into()),
+	}
 }
 
 #[command]
-pub fn show(index: usize, app: AppArg<'_>) {
-	let mut app = app.0.lock().unwrap();
+pub fn show(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	if index >= app.files.len() {
+		return Err(Error::index(index, app.files.len()));
+	}
 	app.current_index = index;
+	Ok(())
 }
 
 #[command]
@@ -85,9 +141,13 @@ fn opt_to_str<'a>(n: Option<impl ToString>) -> String {
 }
 
 #[command]
-pub fn get_page(app: AppArg<'_>) -> Option<Value> {
-	let mut app = app.0.lock().unwrap();
-	let file = app.current_file().ok()?;
+pub fn get_page(app: AppArg<'_>) -> Result<Option<Value>, Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = match app.current_file() {
+		Ok(file) => file,
+		Err(_) => return Ok(None),
+	};
 
 	let title = match file.metadata {
 		Metadata::Id3(ref tag) => tag.title().unwrap_or("").to_string(),
@@ -172,41 +232,9 @@ pub fn get_page(app: AppArg<'_>) -> Option<Value> {
 		Metadata::VorbisComments(ref tag) => tag.get("BPM").unwrap_or("").to_string(),
 	};
 
-	#[derive(Serialize)]
-	struct Comment {
-		text: String,
-		lang: Option<String>,
-		description: Option<String>,
-	}
-	let comments: Vec<Comment> = match file.metadata {
-		Metadata::Id3(ref tag) => tag
-			.comments()
-			.map(|c| Comment {
-				text: c.text.clone(),
-				lang: Some(c.lang.clone()),
-				description: Some(c.description.clone()),
-			})
-			.collect(),
-		Metadata::Mp4(ref tag) => tag
-			.comments()
-			.map(|c| Comment {
-				text: c.to_string(),
-				lang: None,
-				description: None,
-			})
-			.collect(),
-		Metadata::VorbisComments(ref tag) => tag
-			.get_all("COMMENT")
-			.map(|c| Comment {
-				text: c.to_string(),
-				lang: None,
-				description: None,
-			})
-			.collect(),
-	};
-
-	Some(serde_json::json!({
+	Ok(Some(serde_json::json!({
 	  "path": file.path.clone(),
+	  "tag_type": file.metadata.tag_type(),
 	  "title": title,
 	  "artists": artists,
 	  "album": album,
@@ -221,7 +249,9 @@ pub fn get_page(app: AppArg<'_>) -> Option<Value> {
 	  "disc_total": disc_total,
 	  "compilation": compilation,
 	  "bpm": bpm,
-	  "comments": comments,
+	  "comments": comments::get_comments(&file.metadata),
+	  "rating": rating::get_rating(&file.metadata, &popm_email),
+	  "chapters": chapters::get_chapters_of(file),
 	  "frames": file.metadata.get_frames(),
-	}))
+	})))
 }
@@ -0,0 +1,182 @@
+use crate::cmd::AppArg;
+use crate::error::Error;
+use crate::frames::Metadata;
+use crate::throw;
+use id3::TagLike;
+use serde::{Deserialize, Serialize};
+use tauri::command;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Comment {
+	pub text: String,
+	pub lang: Option<String>,
+	pub description: Option<String>,
+	/// iTunes stores internal data like gapless and volume info in comments
+	#[serde(default)]
+	pub protected: bool,
+	/// The Vorbis comment key, so `DESCRIPTION` isn't rewritten as `COMMENT`
+	#[serde(default)]
+	pub key: Option<String>,
+}
+
+const VORBIS_KEYS: [&str; 2] = ["COMMENT", "DESCRIPTION"];
+
+fn is_itunes_internal(description: &str) -> bool {
+	description.starts_with("iTun")
+}
+
+/// Vorbis comments in order, with the key they are stored under
+fn get_vorbis_comments(tag: &lofty::ogg::VorbisComments) -> Vec<(&'static str, String)> {
+	let mut comments = Vec::new();
+	for key in VORBIS_KEYS {
+		for value in tag.get_all(key) {
+			comments.push((key, value.to_string()));
+		}
+	}
+	comments
+}
+
+pub fn get_comments(metadata: &Metadata) -> Vec<Comment> {
+	match metadata {
+		Metadata::Id3(tag) => tag
+			.comments()
+			.map(|c| Comment {
+				text: c.text.clone(),
+				lang: Some(c.lang.clone()),
+				description: Some(c.description.clone()),
+				protected: is_itunes_internal(&c.description),
+				key: None,
+			})
+			.collect(),
+		Metadata::Mp4(tag) => tag
+			.comments()
+			.map(|c| Comment {
+				text: c.to_string(),
+				lang: None,
+				description: None,
+				protected: false,
+				key: None,
+			})
+			.collect(),
+		Metadata::VorbisComments(tag) => get_vorbis_comments(tag)
+			.into_iter()
+			.map(|(key, text)| Comment {
+				text,
+				lang: None,
+				description: None,
+				protected: false,
+				key: Some(key.to_string()),
+			})
+			.collect(),
+	}
+}
+
+/// Replace all comments. Vorbis comments keep the key they were stored under,
+/// and comments without a key are added as COMMENT. ID3 comments need a
+/// unique language and description, or one would replace the other.
+pub fn replace_comments(metadata: &mut Metadata, comments: Vec<Comment>) -> Result<(), Error> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let mut contents: Vec<id3::frame::Comment> = Vec::new();
+			for comment in comments {
+				let content = id3::frame::Comment {
+					lang: match comment.lang {
+						Some(lang) if lang.len() == 3 => lang,
+						_ => "eng".to_string(),
+					},
+					description: comment.description.unwrap_or_default(),
+					text: comment.text,
+				};
+				let same_key = |c: &id3::frame::Comment| {
+					c.lang == content.lang && c.description == content.description
+				};
+				if contents.iter().any(same_key) {
+					throw!(
+						Conflict:
+						"Another comment has the same language ({}) and description (\"{}\")",
+						content.lang,
+						content.description
+					);
+				}
+				contents.push(content);
+			}
+			tag.remove("COMM");
+			for content in contents {
+				tag.add_frame(id3::Frame::with_content(
+					"COMM",
+					id3::Content::Comment(content),
+				));
+			}
+		}
+		Metadata::Mp4(tag) => {
+			tag.remove_comments();
+			for comment in comments {
+				tag.add_comment(comment.text);
+			}
+		}
+		Metadata::VorbisComments(tag) => {
+			for key in VORBIS_KEYS {
+				let _ = tag.remove(key);
+			}
+			for comment in comments {
+				let key = match comment.key {
+					Some(key) if VORBIS_KEYS.contains(&key.as_str()) => key,
+					_ => VORBIS_KEYS[0].to_string(),
+				};
+				tag.push(key, comment.text);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Set the comment at `index`, or add a new comment if `index` is the number
+/// of comments
+#[command]
+pub fn set_comment(index: usize, comment: Comment, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let mut comments = get_comments(&file.metadata);
+	if let Some(description) = &comment.description {
+		if is_itunes_internal(description) {
+			throw!(InvalidInput: "Comments starting with \"iTun\" are reserved for iTunes");
+		}
+	}
+	match comments.get_mut(index) {
+		Some(old) => {
+			if old.protected {
+				throw!(InvalidInput: "This comment is used internally by iTunes and can't be edited");
+			}
+			*old = comment;
+		}
+		None => {
+			if index == comments.len() {
+				comments.push(comment);
+			} else {
+				throw!(IndexOutOfRange: "Index out of range");
+			}
+		}
+	}
+	replace_comments(&mut file.metadata, comments)?;
+	file.dirty = true;
+	Ok(())
+}
+
+#[command]
+pub fn remove_comment(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let mut comments = get_comments(&file.metadata);
+	match comments.get(index) {
+		Some(comment) => {
+			if comment.protected {
+				throw!(InvalidInput: "This comment is used internally by iTunes and can't be removed");
+			}
+		}
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	}
+	comments.remove(index);
+	replace_comments(&mut file.metadata, comments)?;
+	file.dirty = true;
+	Ok(())
+}
@@ -0,0 +1,230 @@
+use crate::cmd::{AppArg, File};
+use crate::error::Error;
+use crate::fields::{self, Field, FIELDS};
+use crate::frames::{Frame, Metadata};
+use crate::image::{self, Picture};
+use crate::spreadsheet;
+use crate::throw;
+use id3::TagLike;
+use serde::{Deserialize, Serialize};
+use tauri::command;
+
+/// Something in a tag that can be compared and reverted on its own
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id")]
+pub enum ChangeTarget {
+	Field(Field),
+	/// Raw frames, atoms or keys with this ID that aren't covered by a field
+	Frame(String),
+	Picture(usize),
+}
+
+#[derive(Serialize)]
+pub enum ChangeKind {
+	Added,
+	Removed,
+	Changed,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+	target: ChangeTarget,
+	kind: ChangeKind,
+	old: String,
+	new: String,
+}
+
+fn change(target: ChangeTarget, old: String, new: String) -> Option<Change> {
+	let kind = match (old.is_empty(), new.is_empty()) {
+		_ if old == new => return None,
+		(true, _) => ChangeKind::Added,
+		(_, true) => ChangeKind::Removed,
+		_ => ChangeKind::Changed,
+	};
+	Some(Change {
+		target,
+		kind,
+		old,
+		new,
+	})
+}
+
+/// Frame values joined per ID, in tag order
+fn frames_by_id(metadata: &Metadata) -> Vec<(String, String)> {
+	let mut frames: Vec<(String, String)> = Vec::new();
+	for frame in metadata.get_frames() {
+		let Frame::Text { id, value } = frame;
+		match frames.iter_mut().find(|(i, _)| *i == id) {
+			Some((_, existing)) => {
+				existing.push_str("\n\n");
+				existing.push_str(&value);
+			}
+			None => frames.push((id, value)),
+		}
+	}
+	frames
+}
+
+fn frame_value<'a>(frames: &'a [(String, String)], id: &str) -> &'a str {
+	match frames.iter().find(|(i, _)| i == id) {
+		Some((_, value)) => value,
+		None => "",
+	}
+}
+
+fn picture_to_string(picture: Option<&Picture>) -> String {
+	let picture = match picture {
+		Some(picture) => picture,
+		None => return String::new(),
+	};
+	let mut s = format!("{}, {} KB", picture.mime_type, picture.data.len() / 1024);
+	if !picture.description.is_empty() {
+		s.push_str(&format!(", {}", picture.description));
+	}
+	s
+}
+
+fn pictures_equal(a: &Picture, b: &Picture) -> bool {
+	a.picture_type == b.picture_type
+		&& a.description == b.description
+		&& a.mime_type == b.mime_type
+		&& a.data == b.data
+}
+
+/// Compare a file's tag to the tag it had when it was opened or last saved
+fn diff(file: &File, popm_email: &str) -> Result<Vec<Change>, Error> {
+	let saved = &file.saved_metadata;
+	let mut changes = Vec::new();
+	// frames changed by a field are only listed as the field
+	let mut frames_base = file.metadata.clone();
+	for field in FIELDS.iter().copied().filter(|f| *f != Field::Artwork) {
+		let old = fields::get_field(saved, field, popm_email);
+		let new = fields::get_field(&file.metadata, field, popm_email);
+		let old_string = spreadsheet::field_to_string(old.clone());
+		let new_string = spreadsheet::field_to_string(new);
+		if let Some(change) = change(ChangeTarget::Field(field), old_string, new_string) {
+			changes.push(change);
+			if let Err(e) = fields::set_field(&mut frames_base, field, old, popm_email) {
+				let message = format!("Error comparing {:?}", field);
+				return Err(e.context(&message).with_path(&file.path));
+			}
+		}
+	}
+
+	let old_frames = frames_by_id(saved);
+	let new_frames = frames_by_id(&frames_base);
+	let mut ids: Vec<&str> = Vec::new();
+	for (id, _) in old_frames.iter().chain(&new_frames) {
+		if !ids.contains(&id.as_str()) {
+			ids.push(id);
+		}
+	}
+	for id in ids {
+		let old = frame_value(&old_frames, id).to_string();
+		let new = frame_value(&new_frames, id).to_string();
+		changes.extend(change(ChangeTarget::Frame(id.to_string()), old, new));
+	}
+
+	let old_pictures = image::get_pictures(saved);
+	let new_pictures = image::get_pictures(&file.metadata);
+	for index in 0..old_pictures.len().max(new_pictures.len()) {
+		let (old, new) = (old_pictures.get(index), new_pictures.get(index));
+		if let (Some(old), Some(new)) = (old, new) {
+			if pictures_equal(old, new) {
+				continue;
+			}
+		}
+		let (old, new) = (picture_to_string(old), picture_to_string(new));
+		// identical strings for different pictures still count as changed
+		let kind = match (old.is_empty(), new.is_empty()) {
+			(true, _) => ChangeKind::Added,
+			(_, true) => ChangeKind::Removed,
+			_ => ChangeKind::Changed,
+		};
+		changes.push(Change {
+			target: ChangeTarget::Picture(index),
+			kind,
+			old,
+			new,
+		});
+	}
+	Ok(changes)
+}
+
+/// List the unsaved changes of a file
+#[command]
+pub fn get_diff(index: usize, app: AppArg<'_>) -> Result<Vec<Change>, Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.file_mut(index)?;
+	diff(file, &popm_email)
+}
+
+/// Replace the frames with this ID by the saved ones
+fn revert_frame(metadata: &mut Metadata, saved: &Metadata, id: &str) {
+	match (metadata, saved) {
+		(Metadata::Id3(tag), Metadata::Id3(saved)) => {
+			let _ = tag.remove(id);
+			// add_frame would drop frames that share a description or type
+			tag.extend(saved.frames().filter(|frame| frame.id() == id).cloned());
+		}
+		(Metadata::Mp4(tag), Metadata::Mp4(saved)) => {
+			let idents: Vec<_> = tag
+				.data()
+				.map(|(ident, _)| ident.clone())
+				.filter(|ident| ident.to_string() == id)
+				.collect();
+			for ident in idents {
+				tag.remove_data_of(&ident);
+			}
+			for (ident, data) in saved.data().filter(|(ident, _)| ident.to_string() == id) {
+				tag.add_data(ident.clone(), data.clone());
+			}
+		}
+		(Metadata::VorbisComments(tag), Metadata::VorbisComments(saved)) => {
+			let _ = tag.remove(id);
+			for (key, value) in saved.items().filter(|(key, _)| *key == id) {
+				tag.push(key.to_string(), value.to_string());
+			}
+		}
+		_ => {}
+	}
+}
+
+/// Put the saved picture back at `index`, or remove an added one
+fn revert_picture(metadata: &mut Metadata, saved: &Metadata, index: usize) -> Result<(), Error> {
+	let mut pictures = image::get_pictures(metadata);
+	let saved_picture = image::get_pictures(saved).into_iter().nth(index);
+	match saved_picture {
+		Some(picture) if index < pictures.len() => pictures[index] = picture,
+		Some(picture) => pictures.push(picture),
+		None if index < pictures.len() => {
+			pictures.remove(index);
+		}
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	}
+	image::replace_pictures(metadata, pictures)
+}
+
+/// Undo one change from `get_diff`. The file stays dirty until all changes
+/// are reverted.
+#[command]
+pub fn revert_change(index: usize, target: ChangeTarget, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.file_mut(index)?;
+	let saved = file.saved_metadata.clone();
+	match target {
+		ChangeTarget::Field(field) => {
+			let value = fields::get_field(&saved, field, &popm_email);
+			fields::set_field(&mut file.metadata, field, value, &popm_email)?;
+		}
+		ChangeTarget::Frame(id) => revert_frame(&mut file.metadata, &saved, &id),
+		ChangeTarget::Picture(picture_index) => {
+			revert_picture(&mut file.metadata, &saved, picture_index)?;
+		}
+	}
+	// the diff doesn't list every frame, so compare the whole tag
+	file.dirty = !file.metadata.tag_eq(&file.saved_metadata);
+	Ok(())
+}
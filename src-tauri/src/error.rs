@@ -0,0 +1,95 @@
+use crate::throw;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// What went wrong, so the frontend can react to it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ErrorKind {
+	/// Reading or writing a file failed
+	Io,
+	/// The file or image format isn't supported, or the tag format doesn't
+	/// support the requested change
+	UnsupportedFormat,
+	/// A tag, image or imported file couldn't be parsed
+	Parse,
+	/// An index doesn't point to an open file, image, chapter etc.
+	IndexOutOfRange,
+	/// The user cancelled, for example by closing a dialog
+	Cancelled,
+	/// Another program changed the file since it was opened or saved, or the
+	/// destination already exists
+	Conflict,
+	/// A value from the frontend was rejected
+	InvalidInput,
+	/// A command panicked earlier while holding the app state
+	Poisoned,
+	Other,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub message: String,
+	/// The file the error is about, if any
+	pub path: Option<PathBuf>,
+}
+impl Error {
+	pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+		Error {
+			kind,
+			message: message.into(),
+			path: None,
+		}
+	}
+	pub fn with_path(mut self, path: &Path) -> Self {
+		self.path = Some(path.to_path_buf());
+		self
+	}
+	/// Prefix the message, like "Error processing artwork: ..."
+	pub fn context(mut self, context: &str) -> Self {
+		self.message = format!("{}: {}", context, self.message);
+		self
+	}
+	/// An IO error, with context like "Error writing file"
+	pub fn io(context: &str, path: &Path, e: io::Error) -> Self {
+		Error::new(ErrorKind::Io, format!("{}: {}", context, e)).with_path(path)
+	}
+	pub fn index(index: usize, len: usize) -> Self {
+		let message = format!("Index {} out of range, length is {}", index, len);
+		Error::new(ErrorKind::IndexOutOfRange, message)
+	}
+	pub fn cancelled() -> Self {
+		Error::new(ErrorKind::Cancelled, "Cancelled")
+	}
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.path {
+			Some(path) => write!(f, "{} ({})", self.message, path.to_string_lossy()),
+			None => write!(f, "{}", self.message),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+/// Errors thrown with a plain message
+impl From<String> for Error {
+	fn from(message: String) -> Self {
+		Error::new(ErrorKind::Other, message)
+	}
+}
+
+/// Lock a mutex without panicking if another thread panicked while holding it
+pub fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Error> {
+	match mutex.lock() {
+		Ok(guard) => Ok(guard),
+		Err(_) => throw!(
+			Poisoned:
+			"The app state is unavailable because of an earlier crash. Please restart the app"
+		),
+	}
+}
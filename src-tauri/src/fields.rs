@@ -0,0 +1,407 @@
+use crate::cmd::AppArg;
+use crate::comments::{self, Comment};
+use crate::error::Error;
+use crate::frames::Metadata;
+use crate::image::{self, Picture};
+use crate::lyrics::{self, Lyrics};
+use crate::rating;
+use crate::throw;
+use id3::TagLike;
+use serde::{Deserialize, Serialize};
+use tauri::command;
+
+/// Fields that can be copied between files of any format
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Field {
+	Title,
+	Artist,
+	Album,
+	AlbumArtist,
+	Composer,
+	Grouping,
+	Genre,
+	Year,
+	TrackNumber,
+	TrackTotal,
+	DiscNumber,
+	DiscTotal,
+	Compilation,
+	Bpm,
+	Comment,
+	Lyrics,
+	Rating,
+	Artwork,
+}
+
+pub const FIELDS: [Field; 18] = [
+	Field::Title,
+	Field::Artist,
+	Field::Album,
+	Field::AlbumArtist,
+	Field::Composer,
+	Field::Grouping,
+	Field::Genre,
+	Field::Year,
+	Field::TrackNumber,
+	Field::TrackTotal,
+	Field::DiscNumber,
+	Field::DiscTotal,
+	Field::Compilation,
+	Field::Bpm,
+	Field::Comment,
+	Field::Lyrics,
+	Field::Rating,
+	Field::Artwork,
+];
+
+#[derive(Clone, Serialize)]
+pub enum FieldValue {
+	/// Empty if the field isn't set
+	Text(Vec<String>),
+	Comments(Vec<Comment>),
+	Lyrics(Vec<Lyrics>),
+	Stars(Option<f64>),
+	Pictures(Vec<Picture>),
+}
+
+impl FieldValue {
+	pub fn is_empty(&self) -> bool {
+		match self {
+			FieldValue::Text(values) => values.is_empty(),
+			FieldValue::Comments(comments) => comments.is_empty(),
+			FieldValue::Lyrics(lyrics) => lyrics.is_empty(),
+			FieldValue::Stars(stars) => stars.is_none(),
+			FieldValue::Pictures(pictures) => pictures.is_empty(),
+		}
+	}
+}
+
+fn id3_text_id(field: Field) -> Option<&'static str> {
+	let id = match field {
+		Field::Title => "TIT2",
+		Field::Artist => "TPE1",
+		Field::Album => "TALB",
+		Field::AlbumArtist => "TPE2",
+		Field::Composer => "TCOM",
+		// non-standard iTunes tag
+		Field::Grouping => "GRP1",
+		Field::Genre => "TCON",
+		Field::Year => "TDRC",
+		Field::Compilation => "TCMP",
+		Field::Bpm => "TBPM",
+		_ => return None,
+	};
+	Some(id)
+}
+
+pub fn vorbis_key(field: Field) -> Option<&'static str> {
+	let key = match field {
+		Field::Title => "TITLE",
+		Field::Artist => "ARTIST",
+		Field::Album => "ALBUM",
+		Field::AlbumArtist => "ALBUMARTIST",
+		Field::Composer => "COMPOSER",
+		Field::Grouping => "GROUPING",
+		Field::Genre => "GENRE",
+		Field::Year => "DATE",
+		Field::TrackNumber => "TRACKNUMBER",
+		Field::TrackTotal => "TRACKTOTAL",
+		Field::DiscNumber => "DISCNUMBER",
+		Field::DiscTotal => "DISCTOTAL",
+		Field::Compilation => "COMPILATION",
+		Field::Bpm => "BPM",
+		_ => return None,
+	};
+	Some(key)
+}
+
+fn opt_to_vec(value: Option<impl ToString>) -> Vec<String> {
+	value.map(|v| v.to_string()).into_iter().collect()
+}
+
+fn get_id3_text(tag: &id3::Tag, field: Field) -> Vec<String> {
+	match field {
+		Field::TrackNumber => opt_to_vec(tag.track()),
+		Field::TrackTotal => opt_to_vec(tag.total_tracks()),
+		Field::DiscNumber => opt_to_vec(tag.disc()),
+		Field::DiscTotal => opt_to_vec(tag.total_discs()),
+		_ => {
+			let id = match id3_text_id(field) {
+				Some(id) => id,
+				None => return Vec::new(),
+			};
+			let fallback = match field {
+				// ID3v2.3 and v2.2 frames that aren't remapped
+				Field::Grouping => "GP1",
+				Field::Year => "TYER",
+				Field::Compilation => "TCP",
+				_ => id,
+			};
+			let text = tag
+				.get(id)
+				.or_else(|| tag.get(fallback))
+				.and_then(|frame| frame.content().text());
+			match text {
+				Some(text) => text.split('\u{0}').map(|s| s.to_string()).collect(),
+				None => Vec::new(),
+			}
+		}
+	}
+}
+
+fn to_vec<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+	values.map(|s| s.to_string()).collect()
+}
+
+fn get_mp4_text(tag: &mp4ameta::Tag, field: Field) -> Vec<String> {
+	match field {
+		Field::Title => opt_to_vec(tag.title()),
+		Field::Artist => to_vec(tag.artists()),
+		Field::Album => opt_to_vec(tag.album()),
+		Field::AlbumArtist => to_vec(tag.album_artists()),
+		Field::Composer => to_vec(tag.composers()),
+		Field::Grouping => to_vec(tag.groupings()),
+		Field::Genre => to_vec(tag.genres()),
+		Field::Year => opt_to_vec(tag.year()),
+		Field::TrackNumber => opt_to_vec(tag.track_number()),
+		Field::TrackTotal => opt_to_vec(tag.total_tracks()),
+		Field::DiscNumber => opt_to_vec(tag.disc_number()),
+		Field::DiscTotal => opt_to_vec(tag.total_discs()),
+		Field::Compilation if tag.compilation() => vec!["1".to_string()],
+		Field::Bpm => opt_to_vec(tag.bpm()),
+		_ => Vec::new(),
+	}
+}
+
+fn get_vorbis_text(tag: &lofty::ogg::VorbisComments, field: Field) -> Vec<String> {
+	let key = match vorbis_key(field) {
+		Some(key) => key,
+		None => return Vec::new(),
+	};
+	let fallback = match field {
+		Field::TrackTotal => "TOTALTRACKS",
+		Field::DiscTotal => "TOTALDISCS",
+		_ => key,
+	};
+	let mut values: Vec<_> = tag.get_all(key).map(|s| s.to_string()).collect();
+	if values.is_empty() {
+		values = tag.get_all(fallback).map(|s| s.to_string()).collect();
+	}
+	values
+}
+
+pub fn get_field(metadata: &Metadata, field: Field, popm_email: &str) -> FieldValue {
+	match field {
+		Field::Comment => FieldValue::Comments(comments::get_comments(metadata)),
+		Field::Lyrics => FieldValue::Lyrics(lyrics::get_unsynced(metadata)),
+		Field::Rating => FieldValue::Stars(rating::get_rating(metadata, popm_email).stars),
+		Field::Artwork => FieldValue::Pictures(image::get_pictures(metadata)),
+		_ => FieldValue::Text(match metadata {
+			Metadata::Id3(tag) => get_id3_text(tag, field),
+			Metadata::Mp4(tag) => get_mp4_text(tag, field),
+			Metadata::VorbisComments(tag) => get_vorbis_text(tag, field),
+		}),
+	}
+}
+
+fn parse_number<T: std::str::FromStr>(field: Field, value: &str) -> Result<T, Error> {
+	match value.trim().parse() {
+		Ok(n) => Ok(n),
+		Err(_) => throw!(InvalidInput: "Invalid {:?} value: {}", field, value),
+	}
+}
+
+fn set_id3_text(tag: &mut id3::Tag, field: Field, values: Vec<String>) -> Result<(), Error> {
+	let first = values.first();
+	match field {
+		Field::TrackNumber => match first {
+			Some(n) => tag.set_track(parse_number(field, n)?),
+			None => tag.remove_track(),
+		},
+		Field::TrackTotal => match first {
+			Some(n) => tag.set_total_tracks(parse_number(field, n)?),
+			None => tag.remove_total_tracks(),
+		},
+		Field::DiscNumber => match first {
+			Some(n) => tag.set_disc(parse_number(field, n)?),
+			None => tag.remove_disc(),
+		},
+		Field::DiscTotal => match first {
+			Some(n) => tag.set_total_discs(parse_number(field, n)?),
+			None => tag.remove_total_discs(),
+		},
+		_ => {
+			let id = match id3_text_id(field) {
+				Some(id) => id,
+				None => throw!(InvalidInput: "{:?} is not a text field", field),
+			};
+			tag.remove(id);
+			if !values.is_empty() {
+				tag.set_text(id, values.join("\u{0}"));
+			}
+		}
+	}
+	Ok(())
+}
+
+fn set_mp4_text(tag: &mut mp4ameta::Tag, field: Field, values: Vec<String>) -> Result<(), Error> {
+	// single value atoms
+	let joined = if values.is_empty() {
+		None
+	} else {
+		Some(values.join("; "))
+	};
+	match field {
+		Field::Title => match joined {
+			Some(title) => tag.set_title(title),
+			None => tag.remove_title(),
+		},
+		Field::Artist => tag.set_artists(values),
+		Field::Album => match joined {
+			Some(album) => tag.set_album(album),
+			None => tag.remove_album(),
+		},
+		Field::AlbumArtist => tag.set_album_artists(values),
+		Field::Composer => tag.set_composers(values),
+		Field::Grouping => tag.set_groupings(values),
+		Field::Genre => {
+			tag.remove_standard_genres();
+			tag.set_genres(values);
+		}
+		Field::Year => match joined {
+			Some(year) => tag.set_year(year),
+			None => tag.remove_year(),
+		},
+		Field::TrackNumber => match values.first() {
+			Some(n) => tag.set_track_number(parse_number(field, n)?),
+			None => tag.remove_track_number(),
+		},
+		Field::TrackTotal => match values.first() {
+			Some(n) => tag.set_total_tracks(parse_number(field, n)?),
+			None => tag.remove_total_tracks(),
+		},
+		Field::DiscNumber => match values.first() {
+			Some(n) => tag.set_disc_number(parse_number(field, n)?),
+			None => tag.remove_disc_number(),
+		},
+		Field::DiscTotal => match values.first() {
+			Some(n) => tag.set_total_discs(parse_number(field, n)?),
+			None => tag.remove_total_discs(),
+		},
+		Field::Compilation => match values.first().map(|s| s.as_str()) {
+			Some("1") => tag.set_compilation(),
+			_ => tag.remove_compilation(),
+		},
+		Field::Bpm => match values.first() {
+			Some(bpm) => tag.set_bpm(parse_number(field, bpm)?),
+			None => tag.remove_bpm(),
+		},
+		_ => throw!(InvalidInput: "{:?} is not a text field", field),
+	}
+	Ok(())
+}
+
+fn set_vorbis_text(
+	tag: &mut lofty::ogg::VorbisComments,
+	field: Field,
+	values: Vec<String>,
+) -> Result<(), Error> {
+	let key = match vorbis_key(field) {
+		Some(key) => key,
+		None => throw!(InvalidInput: "{:?} is not a text field", field),
+	};
+	let _ = tag.remove(key);
+	match field {
+		Field::TrackTotal => {
+			let _ = tag.remove("TOTALTRACKS");
+		}
+		Field::DiscTotal => {
+			let _ = tag.remove("TOTALDISCS");
+		}
+		_ => {}
+	}
+	for value in values {
+		tag.push(key.to_string(), value);
+	}
+	Ok(())
+}
+
+/// Set a field, mapping it to the frame, atom or key of the tag format
+pub fn set_field(
+	metadata: &mut Metadata,
+	field: Field,
+	value: FieldValue,
+	popm_email: &str,
+) -> Result<(), Error> {
+	match (field, value) {
+		(Field::Comment, FieldValue::Comments(new_comments)) => {
+			// iTunes' internal comments belong to the file they were made for
+			let mut all: Vec<_> = comments::get_comments(metadata)
+				.into_iter()
+				.filter(|comment| comment.protected)
+				.collect();
+			all.extend(
+				new_comments
+					.into_iter()
+					.filter(|comment| !comment.protected),
+			);
+			comments::replace_comments(metadata, all)?;
+		}
+		(Field::Lyrics, FieldValue::Lyrics(all)) => lyrics::replace_unsynced(metadata, all)?,
+		(Field::Rating, FieldValue::Stars(stars)) => rating::set_stars(metadata, stars, popm_email),
+		(Field::Artwork, FieldValue::Pictures(pictures)) => {
+			image::replace_pictures(metadata, pictures)?
+		}
+		(field, FieldValue::Text(values)) => {
+			let values: Vec<_> = values.into_iter().filter(|v| !v.is_empty()).collect();
+			match metadata {
+				Metadata::Id3(tag) => set_id3_text(tag, field, values)?,
+				Metadata::Mp4(tag) => set_mp4_text(tag, field, values)?,
+				Metadata::VorbisComments(tag) => set_vorbis_text(tag, field, values)?,
+			}
+		}
+		(field, _) => throw!(InvalidInput: "Wrong value type for {:?}", field),
+	}
+	Ok(())
+}
+
+#[derive(Clone, Serialize)]
+pub struct CopiedField {
+	field: Field,
+	value: FieldValue,
+}
+
+/// Copy fields of the current file. Returns the copied fields that have a
+/// value, so the user can choose which ones to paste.
+#[command]
+pub fn copy_tags(fields: Vec<Field>, app: AppArg<'_>) -> Result<Vec<CopiedField>, Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.current_file()?;
+	let copied: Vec<_> = fields
+		.into_iter()
+		.map(|field| CopiedField {
+			field,
+			value: get_field(&file.metadata, field, &popm_email),
+		})
+		.filter(|copied| !copied.value.is_empty())
+		.collect();
+	app.clipboard = copied.clone();
+	Ok(copied)
+}
+
+/// Paste the chosen fields from the clipboard into the current file
+#[command]
+pub fn paste_tags(fields: Vec<Field>, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let clipboard = app.clipboard.clone();
+	let file = app.current_file()?;
+	for copied in clipboard {
+		if fields.contains(&copied.field) {
+			set_field(&mut file.metadata, copied.field, copied.value, &popm_email)?;
+			file.dirty = true;
+		}
+	}
+	Ok(())
+}
@@ -1,129 +1,774 @@
-use crate::cmd::{AppArg, File};
+use crate::chapters;
+use crate::cmd::{App, AppArg, File};
+use crate::error::{self, Error, ErrorKind};
 use crate::frames::Metadata;
+use crate::save::{self, SaveOptions};
 use crate::throw;
-use lofty::{AudioFile, TagExt};
-use std::fs;
-use std::path::PathBuf;
+use crate::watch::{self, FileWatcher, Fingerprint};
+use lofty::AudioFile;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, FileTimes};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
 use tauri::api::dialog;
-use tauri::command;
+use tauri::{command, State, Window};
 
-fn open_file(path: &PathBuf) -> Result<fs::File, String> {
+fn open_file(path: &Path) -> Result<fs::File, Error> {
 	match fs::File::open(&path) {
 		Ok(f) => Ok(f),
-		Err(e) => throw!("Error opening file {}: {}", path.to_string_lossy(), e),
+		Err(e) => return Err(Error::io("Error opening file", &path, e)),
 	}
 }
 
-fn get_metadata(path: &PathBuf) -> Result<Metadata, String> {
-	let ext = path.extension().unwrap_or_default().to_string_lossy();
-	let path_str = path.to_string_lossy();
-	let metadata = match ext.as_ref() {
+#[derive(Clone, Copy)]
+enum FileType {
+	Id3,
+	Mp4,
+	Opus,
+}
+
+/// ISO BMFF brands of MP4 audio and video. HEIC images and QuickTime movies
+/// use the same container with other brands.
+const MP4_BRANDS: [&[u8; 4]; 15] = [
+	b"M4A ", b"M4B ", b"M4P ", b"M4V ", b"M4VH", b"M4VP", b"mp41", b"mp42", b"isom", b"iso2",
+	b"iso4", b"iso5", b"iso6", b"avc1", b"dash",
+];
+
+/// Whether the major or a compatible brand of an `ftyp` box is MP4. Only the
+/// brands within `header` are checked.
+fn has_mp4_brand(header: &[u8]) -> bool {
+	let box_size = match header.get(0..4) {
+		Some(size) => u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize,
+		None => return false,
+	};
+	let end = box_size.min(header.len());
+	// major brand, then the minor version, then compatible brands
+	let major = header.get(8..12).into_iter();
+	let compatible = header.get(16..end).unwrap_or_default().chunks_exact(4);
+	major
+		.chain(compatible)
+		.any(|brand| MP4_BRANDS.iter().any(|b| &b[..] == brand))
+}
+
+/// Whether the ID3 tag at the start of a file is followed by FLAC data
+fn is_flac_after_id3(file: &mut fs::File, header: &[u8]) -> bool {
+	if header.len() < 10 {
+		return false;
+	}
+	// synchsafe integers have 7 bits per byte
+	let size = header[6..10]
+		.iter()
+		.fold(0, |size, byte| (size << 7) | (*byte & 0x7F) as u64);
+	let footer_size = if header[5] & 0x10 != 0 { 10 } else { 0 };
+	let mut magic = [0; 4];
+	file.seek(SeekFrom::Start(10 + size + footer_size)).is_ok()
+		&& file.read_exact(&mut magic).is_ok()
+		&& &magic == b"fLaC"
+}
+
+/// Detect the file type from its first bytes
+fn detect_file_type(path: &Path) -> Option<FileType> {
+	let mut header = [0; 64];
+	let mut file = fs::File::open(path).ok()?;
+	let len = file.read(&mut header).ok()?;
+	let header = &header[..len];
+	if header.starts_with(b"ID3") {
+		if is_flac_after_id3(&mut file, header) {
+			return None;
+		}
+		Some(FileType::Id3)
+	} else if header.len() >= 12
+		&& &header[0..4] == b"FORM"
+		&& matches!(&header[8..12], b"AIFF" | b"AIFC")
+	{
+		Some(FileType::Id3)
+	} else if header.len() >= 8 && &header[4..8] == b"ftyp" {
+		if has_mp4_brand(header) {
+			Some(FileType::Mp4)
+		} else {
+			None
+		}
+	} else if header.starts_with(b"OggS") && header.windows(8).any(|w| w == b"OpusHead") {
+		Some(FileType::Opus)
+	} else if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 {
+		// MPEG audio frame sync, for MP3s without a tag
+		Some(FileType::Id3)
+	} else {
+		None
+	}
+}
+
+/// Used when the content isn't recognized, like an MP3 with leading junk
+fn file_type_from_extension(path: &Path) -> Option<FileType> {
+	let ext = path
+		.extension()
+		.unwrap_or_default()
+		.to_string_lossy()
+		.to_lowercase();
+	match ext.as_ref() {
+		"mp3" | "aiff" => Some(FileType::Id3),
+		"m4a" | "mp4" | "m4p" | "m4b" | "m4r" | "m4v" => Some(FileType::Mp4),
+		"opus" => Some(FileType::Opus),
+		_ => None,
+	}
+}
+
+/// Errors don't include the path, since they're reported with it
+pub fn get_metadata(path: &Path) -> Result<Metadata, Error> {
+	let file_type = detect_file_type(path).or_else(|| file_type_from_extension(path));
+	let metadata = match file_type {
 		// ID3
-		"mp3" | "aiff" => {
+		Some(FileType::Id3) => {
 			let tag = match id3::Tag::read_from_path(&path) {
 				Ok(tag) => tag,
 				Err(e) => match e.kind {
 					id3::ErrorKind::NoTag => id3::Tag::default(),
-					_ => throw!("Error reading tag for file {}: {}", path_str, e.description),
+					_ => throw!(Parse: "Error reading tag: {}", e.description),
 				},
 			};
 			Metadata::Id3(tag)
 		}
 		// iTunes-style
-		"m4a" | "mp4" | "m4p" | "m4b" | "m4r" | "m4v" => {
+		Some(FileType::Mp4) => {
 			let tag = match mp4ameta::Tag::read_from_path(&path) {
 				Ok(tag) => tag,
 				Err(e) => match e.kind {
 					mp4ameta::ErrorKind::NoTag => mp4ameta::Tag::default(),
-					_ => throw!("Error reading tag for file {}: {}", path_str, e.description),
+					_ => throw!(Parse: "Error reading tag: {}", e.description),
 				},
 			};
 			Metadata::Mp4(tag)
 		}
-		"opus" => {
+		Some(FileType::Opus) => {
 			let mut file = open_file(&path)?;
 			let mut opus = match lofty::ogg::OpusFile::read_from(
 				&mut file,
 				lofty::ParseOptions::new().read_properties(false),
 			) {
 				Ok(f) => f,
-				Err(e) => throw!("Error reading tag for file {}: {}", path_str, e),
+				Err(e) => throw!(Parse: "Error reading tag: {}", e),
 			};
 			Metadata::VorbisComments(opus.remove_vorbis_comments())
 		}
-		_ => throw!("Unsupported file type"),
+		None => throw!(UnsupportedFormat: "Unsupported file type"),
 	};
 	Ok(metadata)
 }
 
-#[command]
-pub async fn open_files(paths: Vec<PathBuf>, app: AppArg<'_>) -> Result<(), String> {
-	let mut app = app.0.lock().unwrap();
-	let initial_len = app.files.len();
-	for path in paths {
-		let is_duplicate = app.files.iter().any(|f| f.path == path);
-		if !is_duplicate {
-			let metadata = get_metadata(&path)?;
-			app.files.push(File {
-				path: path.clone(),
-				dirty: false,
-				metadata: metadata.clone(),
-			});
+/// Read a file's tag, and record what the file looks like on disk
+fn read_file(path: &Path) -> Result<File, Error> {
+	// before anything reads the file and updates the access time
+	let times = save::get_times(path).ok();
+	let metadata = get_metadata(path)?;
+	let disk = watch::fingerprint(path, &metadata)?;
+	let duration_ms = match metadata {
+		Metadata::Id3(_) | Metadata::Mp4(_) => chapters::get_duration_ms(path),
+		Metadata::VorbisComments(_) => None,
+	};
+	Ok(File {
+		path: path.to_path_buf(),
+		dirty: false,
+		saved_metadata: metadata.clone(),
+		metadata,
+		disk: Some(disk),
+		times,
+		duration_ms,
+		changed_on_disk: false,
+	})
+}
+
+#[derive(Default, Deserialize)]
+pub struct OpenOptions {
+	/// Only open files matching one of these globs
+	#[serde(default)]
+	include: Vec<String>,
+	/// Skip files and folders matching any of these globs
+	#[serde(default)]
+	exclude: Vec<String>,
+	#[serde(default)]
+	follow_symlinks: bool,
+	/// How many folders deep to look. 0 only opens the folder's own files.
+	max_depth: Option<usize>,
+}
+
+/// A file that couldn't be opened or saved
+#[derive(Clone, Serialize)]
+pub struct FileError {
+	path: PathBuf,
+	reason: String,
+	kind: ErrorKind,
+}
+impl FileError {
+	pub fn new(path: PathBuf, error: Error) -> Self {
+		FileError {
+			path,
+			reason: error.message,
+			kind: error.kind,
 		}
 	}
-	if initial_len == 0 && app.files.len() >= 1 {
-		app.current_index = app.files.len() - 1;
+	fn unsupported(path: PathBuf) -> Self {
+		let error = Error::new(ErrorKind::UnsupportedFormat, "Unsupported file type");
+		FileError::new(path, error)
 	}
-	Ok(())
 }
 
+#[derive(Default, Serialize)]
+pub struct OpenReport {
+	/// Files that aren't a supported type, or folders that couldn't be read
+	skipped: Vec<FileError>,
+	/// Supported files that couldn't be opened
+	failed: Vec<FileError>,
+	cancelled: bool,
+}
+
+/// Cancel flags of the opens in progress
+#[derive(Default)]
+pub struct OpenState(Mutex<Vec<Arc<AtomicBool>>>);
+
+#[derive(Clone, Serialize)]
+struct OpenProgress {
+	done: usize,
+	total: usize,
+	path: PathBuf,
+}
+
+/// Match a glob with `*`, `**` and `?`. `*` doesn't match `/`, `**` does.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+	match pattern {
+		[] => text.is_empty(),
+		[b'*', b'*', rest @ ..] => {
+			let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+			(0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+		}
+		[b'*', rest @ ..] => {
+			let segment_len = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+			(0..=segment_len).any(|i| glob_match(rest, &text[i..]))
+		}
+		[b'?', rest @ ..] => match text {
+			[c, text @ ..] if *c != b'/' => glob_match(rest, text),
+			_ => false,
+		},
+		[p, rest @ ..] => match text {
+			[c, text @ ..] if c.eq_ignore_ascii_case(p) => glob_match(rest, text),
+			_ => false,
+		},
+	}
+}
+
+/// Globs without a slash match the file name, others match the path relative
+/// to the opened folder
+fn matches_any(globs: &[String], relative_path: &Path) -> bool {
+	let relative = relative_path.to_string_lossy().replace('\\', "/");
+	let name = relative_path
+		.file_name()
+		.unwrap_or_default()
+		.to_string_lossy();
+	globs.iter().any(|glob| {
+		let text = if glob.contains('/') {
+			relative.as_ref()
+		} else {
+			name.as_ref()
+		};
+		glob_match(glob.as_bytes(), text.as_bytes())
+	})
+}
+
+fn walk_dir(
+	root: &Path,
+	dir: &Path,
+	depth: usize,
+	options: &OpenOptions,
+	visited: &mut Vec<PathBuf>,
+	found: &mut Vec<PathBuf>,
+	report: &mut OpenReport,
+) {
+	// symlinks can point to a parent folder
+	if let Ok(canonical) = dir.canonicalize() {
+		if visited.contains(&canonical) {
+			return;
+		}
+		visited.push(canonical);
+	}
+	let mut entries: Vec<_> = match fs::read_dir(dir) {
+		Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+		Err(e) => {
+			let error = Error::io("Error reading folder", dir, e);
+			report
+				.skipped
+				.push(FileError::new(dir.to_path_buf(), error));
+			return;
+		}
+	};
+	entries.sort();
+	for path in entries {
+		let relative = path.strip_prefix(root).unwrap_or(&path);
+		if matches_any(&options.exclude, relative) {
+			continue;
+		}
+		let is_symlink = path.symlink_metadata().map(|m| m.file_type().is_symlink());
+		if is_symlink.unwrap_or(false) && !options.follow_symlinks {
+			continue;
+		}
+		if path.is_dir() {
+			if options.max_depth.map_or(true, |max| depth < max) {
+				walk_dir(root, &path, depth + 1, options, visited, found, report);
+			}
+			continue;
+		}
+		if !options.include.is_empty() && !matches_any(&options.include, relative) {
+			continue;
+		}
+		match detect_file_type(&path) {
+			Some(_) => found.push(path),
+			None => report.skipped.push(FileError::unsupported(path)),
+		}
+	}
+}
+
+/// Put files that were loaded in parallel in the order of `paths`, without
+/// moving other files. The current file stays selected.
+fn sort_loaded(app: &mut App, paths: &[PathBuf]) {
+	let order: HashMap<&PathBuf, usize> = paths.iter().enumerate().map(|(i, p)| (p, i)).collect();
+	let current_path = app.files.get(app.current_index).map(|f| f.path.clone());
+	let files = std::mem::take(&mut app.files);
+	let is_loaded: Vec<_> = files.iter().map(|f| order.contains_key(&f.path)).collect();
+	let (mut loaded, others): (Vec<_>, Vec<_>) =
+		files.into_iter().partition(|f| order.contains_key(&f.path));
+	loaded.sort_by_key(|f| order[&f.path]);
+	let (mut loaded, mut others) = (loaded.into_iter(), others.into_iter());
+	app.files = is_loaded
+		.into_iter()
+		.filter_map(|is_loaded| {
+			if is_loaded {
+				loaded.next()
+			} else {
+				others.next()
+			}
+		})
+		.collect();
+	if let Some(path) = current_path {
+		if let Some(index) = app.files.iter().position(|f| f.path == path) {
+			app.current_index = index;
+		}
+	}
+}
+
+/// Parse files on all cores. Each file is added to the app as soon as it's
+/// parsed, so the app is only locked briefly, and the files are sorted when
+/// all are parsed. Returns the files that failed.
+fn load_files(
+	paths: &[PathBuf],
+	app: &Mutex<App>,
+	cancelled: &AtomicBool,
+	window: &Window,
+) -> Vec<FileError> {
+	let failed = Mutex::new(Vec::new());
+	let next = AtomicUsize::new(0);
+	let done = AtomicUsize::new(0);
+	let thread_count = thread::available_parallelism()
+		.map_or(4, |n| n.get())
+		.min(paths.len());
+	thread::scope(|scope| {
+		for _ in 0..thread_count {
+			scope.spawn(|| loop {
+				if cancelled.load(Ordering::Relaxed) {
+					break;
+				}
+				let path = match paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+					Some(path) => path,
+					None => break,
+				};
+				let result = read_file(path).and_then(|file| {
+					let mut app = error::lock(app)?;
+					if !app.files.iter().any(|f| &f.path == path) {
+						app.files.push(file);
+					}
+					Ok(())
+				});
+				if let Err(e) = result {
+					let error = FileError::new(path.clone(), e);
+					let _ = window.emit("open_error", error.clone());
+					if let Ok(mut failed) = failed.lock() {
+						failed.push(error);
+					}
+				}
+				let progress = OpenProgress {
+					done: done.fetch_add(1, Ordering::Relaxed) + 1,
+					total: paths.len(),
+					path: path.clone(),
+				};
+				let _ = window.emit("open_progress", progress);
+			});
+		}
+	});
+	failed.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Open files and folders. Folders are searched for supported files. Emits
+/// `open_progress` and `open_error` events while files are parsed.
+#[command]
+pub async fn open_files(
+	paths: Vec<PathBuf>,
+	options: Option<OpenOptions>,
+	window: Window,
+	app: AppArg<'_>,
+	open_state: State<'_, OpenState>,
+	watcher: State<'_, FileWatcher>,
+) -> Result<OpenReport, Error> {
+	let options = options.unwrap_or_default();
+	let watched_app = app.0.clone();
+	let app = app.0.clone();
+	// each open has its own flag, so a new open doesn't undo a cancel
+	let cancelled = Arc::new(AtomicBool::new(false));
+	error::lock(&open_state.0)?.push(cancelled.clone());
+	let open_cancelled = cancelled.clone();
+	let task = tauri::async_runtime::spawn_blocking(move || -> Result<OpenReport, Error> {
+		let mut report = OpenReport::default();
+		let mut file_paths = Vec::new();
+		for path in paths {
+			if path.is_dir() {
+				let mut visited = Vec::new();
+				walk_dir(
+					&path,
+					&path,
+					0,
+					&options,
+					&mut visited,
+					&mut file_paths,
+					&mut report,
+				);
+			} else if detect_file_type(&path)
+				.or_else(|| file_type_from_extension(&path))
+				.is_none()
+			{
+				report.skipped.push(FileError::unsupported(path));
+			} else {
+				file_paths.push(path);
+			}
+		}
+		let initial_len = {
+			let app = error::lock(&app)?;
+			file_paths.retain(|path| !app.files.iter().any(|f| &f.path == path));
+			app.files.len()
+		};
+		let mut seen = HashSet::new();
+		file_paths.retain(|path| seen.insert(path.clone()));
+		report.failed = load_files(&file_paths, &app, &cancelled, &window);
+		let mut app = error::lock(&app)?;
+		sort_loaded(&mut app, &file_paths);
+		if initial_len == 0 && app.files.len() >= 1 {
+			app.current_index = 0;
+		}
+		report.cancelled = cancelled.load(Ordering::Relaxed);
+		Ok(report)
+	});
+	let result = task.await;
+	error::lock(&open_state.0)?.retain(|flag| !Arc::ptr_eq(flag, &open_cancelled));
+	let result = match result {
+		Ok(result) => result,
+		Err(e) => throw!(Io: "Error opening files: {}", e),
+	};
+	watch::watch_files(&watcher, &error::lock(&watched_app)?.files);
+	result
+}
+
+/// Stop opening files. Files that are already parsed stay open.
+#[command]
+pub fn cancel_open(open_state: State<'_, OpenState>) -> Result<(), Error> {
+	for cancelled in error::lock(&open_state.0)?.iter() {
+		cancelled.store(true, Ordering::Relaxed);
+	}
+	Ok(())
+}
 #[command]
-pub async fn close_file(index: usize, app: AppArg<'_>) -> Result<(), String> {
-	let mut app = app.0.lock().unwrap();
+pub async fn close_file(
+	index: usize,
+	app: AppArg<'_>,
+	watcher: State<'_, FileWatcher>,
+) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	if index >= app.files.len() {
+		return Err(Error::index(index, app.files.len()));
+	}
 	app.files.remove(index);
 	if app.current_index >= index && index >= 1 {
 		app.current_index -= 1;
 	}
+	watch::watch_files(&watcher, &app.files);
+	Ok(())
+}
+
+/// What is needed to save a file without locking the app
+struct SaveSnapshot {
+	path: PathBuf,
+	metadata: Metadata,
+	disk: Option<Fingerprint>,
+	times: Option<FileTimes>,
+}
+impl SaveSnapshot {
+	fn of(file: &File) -> Self {
+		SaveSnapshot {
+			path: file.path.clone(),
+			metadata: file.metadata.clone(),
+			disk: file.disk.clone(),
+			times: file.times,
+		}
+	}
+}
+
+/// What a file looks like on disk after it was saved
+struct Saved {
+	disk: Option<Fingerprint>,
+	times: Option<FileTimes>,
+}
+
+/// Save a file to `dest`. Unless `overwrite` is set, this fails if another
+/// program changed the file since it was opened or saved.
+fn write_file(
+	snapshot: &SaveSnapshot,
+	dest: &Path,
+	options: &SaveOptions,
+	overwrite: bool,
+) -> Result<Saved, Error> {
+	let changed = match &snapshot.disk {
+		Some(disk) => watch::is_changed(&snapshot.path, disk),
+		None => false,
+	};
+	if changed && !overwrite {
+		let message = "This file was changed by another program";
+		return Err(Error::new(ErrorKind::Conflict, message).with_path(&snapshot.path));
+	}
+	let (path, metadata, times) = (&snapshot.path, &snapshot.metadata, snapshot.times);
+	save::save_atomic(metadata, path, dest, times, options)?;
+	Ok(Saved {
+		times: save::get_times(dest).ok(),
+		disk: watch::fingerprint_written(dest).ok(),
+	})
+}
+
+/// Record that `file` was saved to `dest` with the tag `saved_metadata`. The
+/// file stays dirty if it was edited while saving.
+fn mark_saved(file: &mut File, dest: PathBuf, saved_metadata: Metadata, saved: Saved) {
+	file.path = dest;
+	file.times = saved.times;
+	file.disk = saved.disk;
+	file.dirty = !file.metadata.tag_eq(&saved_metadata);
+	file.saved_metadata = saved_metadata;
+	file.changed_on_disk = false;
+}
+
+fn save_to(
+	file: &mut File,
+	dest: PathBuf,
+	options: &SaveOptions,
+	overwrite: bool,
+) -> Result<(), Error> {
+	let snapshot = SaveSnapshot::of(file);
+	match write_file(&snapshot, &dest, options, overwrite) {
+		Ok(saved) => {
+			mark_saved(file, dest, snapshot.metadata, saved);
+			Ok(())
+		}
+		Err(e) => {
+			if e.kind == ErrorKind::Conflict {
+				file.changed_on_disk = true;
+			}
+			Err(e)
+		}
+	}
+}
+
+#[derive(Deserialize, PartialEq)]
+pub enum SaveMode {
+	Save,
+	SaveAs,
+	/// Save to a new path, but keep the open file at its current path
+	SaveCopy,
+}
+
+/// Ask where to save a file, starting at its current name and folder
+fn ask_save_path(path: &Path, title: &str) -> Option<PathBuf> {
+	let ext = path.extension().unwrap_or_default().to_string_lossy();
+	let mut builder = dialog::FileDialogBuilder::new()
+		.set_title(title)
+		.add_filter("Audio/Video file", &[&ext]);
+	if let Some(dir) = path.parent() {
+		builder = builder.set_directory(dir);
+	}
+	if let Some(file_name) = path.file_name() {
+		builder = builder.set_file_name(&file_name.to_string_lossy());
+	}
+	let (sender, receiver) = std::sync::mpsc::channel();
+	builder.save_file(move |p| {
+		let _ = sender.send(p);
+	});
+	receiver.recv().unwrap_or_default()
+}
+
+/// Save a file. If `overwrite` is set, changes made by other programs are
+/// overwritten. A copy is written from the file on disk, so it doesn't
+/// check for changes made by other programs.
+#[command]
+pub async fn save_file(
+	index: usize,
+	mode: SaveMode,
+	overwrite: bool,
+	app: AppArg<'_>,
+	watcher: State<'_, FileWatcher>,
+) -> Result<(), Error> {
+	let path = app.lock()?.file_mut(index)?.path.clone();
+	let dest = match mode {
+		SaveMode::Save => path.clone(),
+		SaveMode::SaveAs | SaveMode::SaveCopy => {
+			let title = if mode == SaveMode::SaveAs {
+				"Save As"
+			} else {
+				"Save a Copy"
+			};
+			// the app isn't locked while the dialog is open
+			match ask_save_path(&path, title) {
+				Some(dest) => dest,
+				None => return Err(Error::cancelled()),
+			}
+		}
+	};
+	let mut app = app.lock()?;
+	let save_options = app.save_options.clone();
+	let file = app.file_mut(index)?;
+	if file.path != path {
+		throw!(Conflict: "The file was closed while choosing where to save it");
+	}
+	if mode == SaveMode::SaveCopy && dest != file.path {
+		return save::save_atomic(&file.metadata, &file.path, &dest, file.times, &save_options);
+	}
+	save_to(file, dest, &save_options, overwrite)?;
+	if mode == SaveMode::SaveAs {
+		watch::watch_files(&watcher, &app.files);
+	}
 	Ok(())
 }
 
+/// Save every file with unsaved changes. Files that fail stay dirty, and
+/// are returned so they can be reported together. Files changed by other
+/// programs aren't overwritten. The app is only locked between files.
 #[command]
-pub async fn save_file(index: usize, save_as: bool, app: AppArg<'_>) -> Result<(), String> {
-	let mut app = app.0.lock().unwrap();
-	let mut file = app.files.get_mut(index).unwrap();
-	if save_as {
-		let ext = file.path.extension().unwrap_or_default().to_string_lossy();
-		let (sender, receiver) = std::sync::mpsc::channel();
-		dialog::FileDialogBuilder::new()
-			.set_file_name("report.kryp")
-			.add_filter("Audio/Video file", &[&ext])
-			.save_file(move |p| {
-				sender.send(p).unwrap();
-			});
-		let new_path = match receiver.recv().unwrap_or_default() {
-			Some(file_path) => file_path,
-			None => return Ok(()),
-		};
-		match fs::copy(&file.path, &new_path) {
-			Ok(_) => {}
-			Err(e) => throw!("Error copying file: {}", e),
+pub async fn save_all(app: AppArg<'_>) -> Result<Vec<FileError>, Error> {
+	let (snapshots, save_options) = {
+		let app = app.lock()?;
+		let snapshots: Vec<_> = app
+			.files
+			.iter()
+			.filter(|file| file.dirty)
+			.map(SaveSnapshot::of)
+			.collect();
+		(snapshots, app.save_options.clone())
+	};
+	let mut failed = Vec::new();
+	for snapshot in snapshots {
+		let result = write_file(&snapshot, &snapshot.path, &save_options, false);
+		let mut app = app.lock()?;
+		// the file may have been closed while saving
+		let file = app.files.iter_mut().find(|file| file.path == snapshot.path);
+		match (result, file) {
+			(Ok(saved), Some(file)) => {
+				let path = snapshot.path.clone();
+				mark_saved(file, path, snapshot.metadata, saved);
+			}
+			(Ok(_), None) => {}
+			(Err(e), file) => {
+				if let (ErrorKind::Conflict, Some(file)) = (e.kind, file) {
+					file.changed_on_disk = true;
+				}
+				failed.push(FileError::new(snapshot.path, e));
+			}
 		}
-		file.path = new_path;
 	}
-	match file.metadata {
-		Metadata::Id3(ref tag) => match tag.write_to_path(&file.path, id3::Version::Id3v24) {
-			Ok(_) => {}
-			Err(e) => throw!("Error saving file: {}", e.description),
-		},
-		Metadata::Mp4(ref tag) => match tag.write_to_path(&file.path) {
-			Ok(_) => {}
-			Err(e) => throw!("Error saving file: {}", e.description),
-		},
-		Metadata::VorbisComments(ref tag) => match tag.save_to_path(&file.path) {
-			Ok(_) => {}
-			Err(e) => throw!("Error saving file: {}", e.to_string()),
-		},
+	Ok(failed)
+}
+
+#[derive(Default, Serialize)]
+pub struct CopyReport {
+	written: Vec<PathBuf>,
+	failed: Vec<FileError>,
+}
+
+/// Write a copy of every open file with its current tags into `dir`,
+/// keeping the file names. The open files and their paths don't change,
+/// and existing files are only replaced if `overwrite` is set. Only
+/// "already exists" failures are conflicts, since overwriting fixes them.
+/// The app isn't locked while writing.
+#[command]
+pub async fn save_copies(
+	dir: PathBuf,
+	overwrite: bool,
+	app: AppArg<'_>,
+) -> Result<CopyReport, Error> {
+	let (snapshots, save_options) = {
+		let app = app.lock()?;
+		let snapshots: Vec<_> = app.files.iter().map(SaveSnapshot::of).collect();
+		(snapshots, app.save_options.clone())
+	};
+	// compare real paths, so another name for an open file's folder is caught
+	let dir = match dir.canonicalize() {
+		Ok(dir) => dir,
+		Err(e) => return Err(Error::io("Error opening folder", &dir, e)),
+	};
+	let open_paths: Vec<_> = snapshots
+		.iter()
+		.map(|snapshot| {
+			snapshot
+				.path
+				.canonicalize()
+				.unwrap_or_else(|_| snapshot.path.clone())
+		})
+		.collect();
+	let mut report = CopyReport::default();
+	for (snapshot, real_path) in snapshots.iter().zip(&open_paths) {
+		let dest = match snapshot.path.file_name() {
+			Some(file_name) => dir.join(file_name),
+			None => continue,
+		};
+		let result = if &dest == real_path {
+			Err(Error::new(
+				ErrorKind::InvalidInput,
+				"The file is already in this folder",
+			))
+		} else if open_paths.contains(&dest) {
+			Err(Error::new(
+				ErrorKind::InvalidInput,
+				"Another open file is at this path",
+			))
+		} else if report.written.contains(&dest) {
+			Err(Error::new(
+				ErrorKind::InvalidInput,
+				"Another open file has the same name",
+			))
+		} else if !overwrite && dest.exists() {
+			Err(Error::new(
+				ErrorKind::Conflict,
+				"A file with this name already exists",
+			))
+		} else {
+			let (path, metadata, times) = (&snapshot.path, &snapshot.metadata, snapshot.times);
+			save::save_atomic(metadata, path, &dest, times, &save_options)
+		};
+		match result {
+			Ok(()) => report.written.push(dest),
+			Err(e) => report.failed.push(FileError::new(snapshot.path.clone(), e)),
+		}
 	}
-	file.dirty = false;
+	Ok(report)
+}
+
+/// Discard unsaved changes by reading the file from disk again
+#[command]
+pub async fn revert_file(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.file_mut(index)?;
+	*file = read_file(&file.path).map_err(|e| e.with_path(&file.path))?;
 	Ok(())
 }
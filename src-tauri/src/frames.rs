@@ -1,5 +1,7 @@
 use lofty::ogg::VorbisComments;
 use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 #[derive(Clone)]
 pub enum Metadata {
@@ -15,6 +17,100 @@ impl Metadata {
 			Self::VorbisComments(vorbis_comments) => get_vorbis_comments_frames(vorbis_comments),
 		}
 	}
+	pub fn tag_type(&self) -> &'static str {
+		match self {
+			Self::Id3(_) => "Id3",
+			Self::Mp4(_) => "Mp4",
+			Self::VorbisComments(_) => "VorbisComments",
+		}
+	}
+	/// Whether the whole tags are the same, including frames, atoms and
+	/// pictures that aren't shown as fields
+	pub fn tag_eq(&self, other: &Metadata) -> bool {
+		match (self, other) {
+			(Self::Id3(a), Self::Id3(b)) => a == b,
+			(Self::Mp4(a), Self::Mp4(b)) => {
+				let chapters = |list: &[mp4ameta::Chapter]| -> Vec<_> {
+					list.iter().map(|c| (c.start, c.title.clone())).collect()
+				};
+				a.data()
+					.map(|(ident, data)| (ident, mp4_data_bytes(data)))
+					.eq(b.data().map(|(ident, data)| (ident, mp4_data_bytes(data))))
+					&& chapters(a.chapter_list()) == chapters(b.chapter_list())
+					&& chapters(a.chapter_track()) == chapters(b.chapter_track())
+			}
+			(Self::VorbisComments(a), Self::VorbisComments(b)) => {
+				let pictures = |tag: &VorbisComments| -> Vec<_> {
+					tag.pictures()
+						.iter()
+						.map(|(pic, _info)| {
+							let description = pic.description().map(|s| s.to_string());
+							let mime_type = pic.mime_type().as_str().to_string();
+							(
+								pic.pic_type().as_u8(),
+								description,
+								mime_type,
+								pic.data().to_vec(),
+							)
+						})
+						.collect()
+				};
+				a.vendor() == b.vendor() && a.items().eq(b.items()) && pictures(a) == pictures(b)
+			}
+			_ => false,
+		}
+	}
+	/// Hash of the whole tag, to tell if a file's tag was changed on disk
+	/// without keeping a copy of it
+	pub fn tag_hash(&self) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		match self {
+			Self::Id3(tag) => {
+				let mut bytes = Vec::new();
+				if let Err(e) = tag.write_to(&mut bytes, tag.version()) {
+					e.to_string().hash(&mut hasher);
+				}
+				bytes.hash(&mut hasher);
+			}
+			Self::Mp4(tag) => {
+				for (ident, data) in tag.data() {
+					(ident.to_string(), mp4_data_bytes(data)).hash(&mut hasher);
+				}
+				for list in [tag.chapter_list(), tag.chapter_track()] {
+					list.len().hash(&mut hasher);
+					for chapter in list {
+						(chapter.start, &chapter.title).hash(&mut hasher);
+					}
+				}
+			}
+			Self::VorbisComments(tag) => {
+				tag.vendor().hash(&mut hasher);
+				for item in tag.items() {
+					item.hash(&mut hasher);
+				}
+				for (pic, _info) in tag.pictures() {
+					pic.pic_type().as_u8().hash(&mut hasher);
+					pic.description().hash(&mut hasher);
+					pic.mime_type().as_str().hash(&mut hasher);
+					pic.data().hash(&mut hasher);
+				}
+			}
+		}
+		hasher.finish()
+	}
+}
+
+/// The data type and bytes of an MP4 atom's data, for comparing
+fn mp4_data_bytes(data: &mp4ameta::Data) -> (u8, &[u8]) {
+	match data {
+		mp4ameta::Data::Reserved(bytes) => (0, bytes),
+		mp4ameta::Data::Utf8(s) => (1, s.as_bytes()),
+		mp4ameta::Data::Utf16(s) => (2, s.as_bytes()),
+		mp4ameta::Data::Jpeg(bytes) => (13, bytes),
+		mp4ameta::Data::Png(bytes) => (14, bytes),
+		mp4ameta::Data::BeSigned(bytes) => (21, bytes),
+		mp4ameta::Data::Bmp(bytes) => (27, bytes),
+	}
 }
 
 #[derive(Serialize)]
@@ -38,22 +134,58 @@ fn get_id3_frames(tag: &id3::Tag) -> Vec<Frame> {
 			id3::Content::Link(_) => {}
 			// !TODO
 			id3::Content::ExtendedLink(_) => {}
-			// !TODO
-			id3::Content::Comment(_) => {}
-			// !TODO
-			id3::Content::Lyrics(_) => {}
-			// !TODO
-			id3::Content::SynchronisedLyrics(_) => {}
+			id3::Content::Comment(comment) => {
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value: comment.to_string(),
+				});
+			}
+			id3::Content::Lyrics(lyrics) => {
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value: lyrics.text.clone(),
+				});
+			}
+			id3::Content::SynchronisedLyrics(lyrics) => {
+				let mut value = String::new();
+				for (time, text) in &lyrics.content {
+					value.push_str(&format!("{} {}\n", time, text));
+				}
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value,
+				});
+			}
 			// !TODO
 			id3::Content::Picture(_) => {}
 			// !TODO
 			id3::Content::EncapsulatedObject(_) => {}
 			// !TODO
 			id3::Content::Unknown(_) => {}
-			// !TODO
-			id3::Content::Popularimeter(_) => {}
-			// !TODO
-			id3::Content::Chapter(_) => {}
+			id3::Content::Popularimeter(popm) => {
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value: format!("{}: {} ({} plays)", popm.user, popm.rating, popm.counter),
+				});
+			}
+			id3::Content::Chapter(chapter) => {
+				let title = chapter
+					.frames
+					.iter()
+					.find(|frame| frame.id() == "TIT2")
+					.and_then(|frame| frame.content().text())
+					.unwrap_or("");
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value: format!("{}-{} ms: {}", chapter.start_time, chapter.end_time, title),
+				});
+			}
+			id3::Content::TableOfContents(toc) => {
+				frames.push(Frame::Text {
+					id: frame.id().to_string(),
+					value: toc.elements.join(", "),
+				});
+			}
 			// !TODO
 			id3::Content::MpegLocationLookupTable(_) => {}
 
@@ -1,54 +1,152 @@
+use crate::artwork::{self, ProcessOptions};
 use crate::cmd::AppArg;
+use crate::error::{Error, ErrorKind};
+use crate::files::FileError;
 use crate::frames::Metadata;
 use crate::throw;
-use base64::{self, Engine};
 use id3::TagLike;
 use lofty::ogg::OggPictureStorage;
 use serde::Serialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use tauri::command;
 
 #[derive(Serialize)]
 pub struct Image {
 	index: usize,
 	total_images: usize,
-	data: String,
+	/// Changes when the image data changes, for cache busting `art://` URLs
+	version: String,
 	mime_type: String,
 	description: Option<String>,
 	picture_type: Option<String>,
+	picture_type_id: Option<u8>,
+	#[serde(flatten)]
+	info: artwork::ImageInfo,
+}
+
+fn id3_picture_type(n: u8) -> id3::frame::PictureType {
+	use id3::frame::PictureType;
+	match n {
+		0 => PictureType::Other,
+		1 => PictureType::Icon,
+		2 => PictureType::OtherIcon,
+		3 => PictureType::CoverFront,
+		4 => PictureType::CoverBack,
+		5 => PictureType::Leaflet,
+		6 => PictureType::Media,
+		7 => PictureType::LeadArtist,
+		8 => PictureType::Artist,
+		9 => PictureType::Conductor,
+		10 => PictureType::Band,
+		11 => PictureType::Composer,
+		12 => PictureType::Lyricist,
+		13 => PictureType::RecordingLocation,
+		14 => PictureType::DuringRecording,
+		15 => PictureType::DuringPerformance,
+		16 => PictureType::ScreenCapture,
+		17 => PictureType::BrightFish,
+		18 => PictureType::Illustration,
+		19 => PictureType::BandLogo,
+		20 => PictureType::PublisherLogo,
+		n => PictureType::Undefined(n),
+	}
+}
+
+/// Index of the front cover, or the first image if there is no front cover
+pub fn default_index(metadata: &Metadata) -> Option<usize> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let mut index = match tag.pictures().next() {
+				Some(_pic) => 0,
+				None => return None,
+			};
+			for (i, current_pic) in tag.pictures().enumerate() {
+				if current_pic.picture_type == id3::frame::PictureType::CoverFront {
+					index = i;
+					break;
+				}
+			}
+			Some(index)
+		}
+		Metadata::Mp4(tag) => match tag.artwork() {
+			Some(_artwork) => Some(0),
+			None => None,
+		},
+		Metadata::VorbisComments(tag) => {
+			if tag.pictures().len() == 0 {
+				return None;
+			}
+			let front_cover = tag
+				.pictures()
+				.iter()
+				.position(|(pic, _info)| pic.pic_type() == lofty::PictureType::CoverFront);
+			Some(front_cover.unwrap_or(0))
+		}
+	}
+}
+
+/// Bytes and MIME type of the image at `index`
+pub fn get_image_data(metadata: &Metadata, index: usize) -> Option<(&[u8], String)> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let pic = tag.pictures().nth(index)?;
+			Some((&pic.data, pic.mime_type.clone()))
+		}
+		Metadata::Mp4(tag) => {
+			let artwork = tag.artworks().nth(index)?;
+			let mime_type = match artwork.fmt {
+				mp4ameta::ImgFmt::Bmp => "image/bmp",
+				mp4ameta::ImgFmt::Jpeg => "image/jpeg",
+				mp4ameta::ImgFmt::Png => "image/png",
+			};
+			Some((artwork.data, mime_type.to_string()))
+		}
+		Metadata::VorbisComments(tag) => {
+			let (pic, _info) = tag.pictures().get(index)?;
+			Some((pic.data(), pic.mime_type().as_str().to_string()))
+		}
+	}
+}
+
+pub fn image_count(metadata: &Metadata) -> usize {
+	match metadata {
+		Metadata::Id3(tag) => tag.pictures().count(),
+		Metadata::Mp4(tag) => tag.artworks().count(),
+		Metadata::VorbisComments(tag) => tag.pictures().len(),
+	}
+}
+
+/// APIC picture type number of the image at `index`. MP4 has no picture types.
+fn picture_type_id(metadata: &Metadata, index: usize) -> Option<u8> {
+	match metadata {
+		Metadata::Id3(tag) => Some(tag.pictures().nth(index)?.picture_type.into()),
+		Metadata::Mp4(_) => None,
+		Metadata::VorbisComments(tag) => Some(tag.pictures().get(index)?.0.pic_type().as_u8()),
+	}
+}
+
+fn extension_for(mime_type: &str) -> Option<&'static str> {
+	match mime_type {
+		"image/jpeg" | "image/jpg" => Some("jpg"),
+		"image/png" => Some("png"),
+		"image/bmp" => Some("bmp"),
+		"image/gif" => Some("gif"),
+		"image/tiff" => Some("tiff"),
+		"image/webp" => Some("webp"),
+		_ => None,
+	}
 }
 
 #[command]
-pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>, String> {
-	let mut app = app.0.lock().unwrap();
+pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>, Error> {
+	let mut app = app.lock()?;
 	let file = app.current_file()?;
 	let index = match index {
 		Some(index) => index,
-		None => match file.metadata {
-			Metadata::Id3(ref tag) => {
-				let mut index = match tag.pictures().next() {
-					Some(_pic) => 0,
-					None => return Ok(None),
-				};
-				for (i, current_pic) in tag.pictures().enumerate() {
-					if current_pic.picture_type == id3::frame::PictureType::CoverFront {
-						index = i;
-						break;
-					}
-				}
-				index
-			}
-			Metadata::Mp4(ref tag) => match tag.artwork() {
-				Some(_artwork) => 0,
-				None => return Ok(None),
-			},
-			Metadata::VorbisComments(ref tag) => {
-				if tag.pictures().len() == 0 {
-					return Ok(None);
-				}
-				0
-			}
+		None => match default_index(&file.metadata) {
+			Some(index) => index,
+			None => return Ok(None),
 		},
 	};
 	let image_option = match file.metadata {
@@ -56,10 +154,12 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 			Some(pic) => Some(Image {
 				index,
 				total_images: tag.pictures().count(),
-				data: base64::engine::general_purpose::STANDARD.encode(&pic.data),
+				version: format!("{:x}", artwork::hash_bytes(&pic.data)),
 				mime_type: pic.mime_type.clone(),
 				description: Some(pic.description.clone()),
 				picture_type: Some(pic.picture_type.to_string()),
+				picture_type_id: Some(pic.picture_type.into()),
+				info: artwork::get_info(&pic.data),
 			}),
 			None => None,
 		},
@@ -67,7 +167,7 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 			Some(artwork) => Some(Image {
 				index,
 				total_images: tag.artworks().count(),
-				data: base64::engine::general_purpose::STANDARD.encode(&artwork.data),
+				version: format!("{:x}", artwork::hash_bytes(artwork.data)),
 				mime_type: match artwork.fmt {
 					mp4ameta::ImgFmt::Bmp => "image/bmp".to_string(),
 					mp4ameta::ImgFmt::Jpeg => "image/jpeg".to_string(),
@@ -75,6 +175,8 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 				},
 				description: None,
 				picture_type: None,
+				picture_type_id: None,
+				info: artwork::get_info(&artwork.data),
 			}),
 			None => None,
 		},
@@ -82,16 +184,18 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 			Some((pic, _info)) => Some(Image {
 				index,
 				total_images: tag.pictures().len(),
-				data: base64::engine::general_purpose::STANDARD.encode(pic.data()),
+				version: format!("{:x}", artwork::hash_bytes(pic.data())),
 				mime_type: match pic.mime_type() {
 					lofty::MimeType::Png => "image/png".to_string(),
 					lofty::MimeType::Jpeg => "image/jpeg".to_string(),
 					lofty::MimeType::Tiff => "image/tiff".to_string(),
 					lofty::MimeType::Bmp => "image/bmp".to_string(),
 					lofty::MimeType::Gif => "image/gif".to_string(),
-					lofty::MimeType::Unknown(unknown) => throw!("Unknown picture type {unknown}"),
-					lofty::MimeType::None => throw!("No picture type"),
-					_ => throw!("Unsupported picture type"),
+					lofty::MimeType::Unknown(unknown) => {
+						throw!(UnsupportedFormat: "Unknown picture type {unknown}")
+					}
+					lofty::MimeType::None => throw!(UnsupportedFormat: "No picture type"),
+					_ => throw!(UnsupportedFormat: "Unsupported picture type"),
 				},
 				description: pic.description().map(|s| s.to_string()),
 				picture_type: Some(match pic.pic_type() {
@@ -116,9 +220,11 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 					lofty::PictureType::Illustration => "Illustration".to_string(),
 					lofty::PictureType::BandLogo => "Band logo".to_string(),
 					lofty::PictureType::PublisherLogo => "Publisher logo".to_string(),
-					lofty::PictureType::Undefined(u) => throw!("Undefined type {u}"),
-					_ => throw!("Unsupported picture type {:?}", pic.pic_type()),
+					lofty::PictureType::Undefined(u) => throw!(Parse: "Undefined type {u}"),
+					_ => throw!(UnsupportedFormat: "Unsupported picture type {:?}", pic.pic_type()),
 				}),
+				picture_type_id: Some(pic.pic_type().as_u8()),
+				info: artwork::get_info(pic.data()),
 			}),
 			None => None,
 		},
@@ -126,70 +232,269 @@ pub fn get_image(index: Option<usize>, app: AppArg<'_>) -> Result<Option<Image>,
 	Ok(image_option)
 }
 
-#[command]
-pub fn remove_image(index: usize, app: AppArg<'_>) -> Result<(), String> {
-	let mut app = app.0.lock().unwrap();
-	let file = app.current_file().unwrap();
-	match file.metadata {
-		Metadata::Id3(ref mut tag) => {
-			let mut pic_frames: Vec<_> = tag
+/// Replace the APIC frames. `add_frame` isn't used since it replaces any
+/// picture with the same picture type.
+pub fn set_id3_pictures(tag: &mut id3::Tag, pic_frames: Vec<id3::Frame>) {
+	tag.remove_all_pictures();
+	tag.extend(pic_frames);
+}
+
+/// Rebuild the image list from the images at the indexes in `order`. Images
+/// that aren't in `order` are removed.
+fn rearrange_images(metadata: &mut Metadata, order: &[usize]) {
+	fn pick<T: Clone>(items: &[T], order: &[usize]) -> Vec<T> {
+		order.iter().map(|i| items[*i].clone()).collect()
+	}
+	match metadata {
+		Metadata::Id3(tag) => {
+			let pic_frames: Vec<_> = tag
 				.frames()
 				.filter(|frame| frame.content().picture().is_some())
 				.map(|frame| frame.clone())
 				.collect();
-			pic_frames.remove(index);
-			tag.remove_all_pictures();
-			for pic_frame in pic_frames {
-				tag.add_frame(pic_frame);
-			}
+			set_id3_pictures(tag, pick(&pic_frames, order));
 		}
-		Metadata::Mp4(ref mut tag) => {
-			let mut artworks: Vec<_> = tag.take_artworks().collect();
-			artworks.remove(index);
-			tag.set_artworks(artworks);
+		Metadata::Mp4(tag) => {
+			let artworks: Vec<_> = tag.take_artworks().collect();
+			tag.set_artworks(pick(&artworks, order));
 		}
-		Metadata::VorbisComments(ref mut tag) => {
-			tag.remove_picture(index);
+		Metadata::VorbisComments(tag) => {
+			let pictures = tag.pictures().to_vec();
+			while !tag.pictures().is_empty() {
+				tag.remove_picture(0);
+			}
+			for (i, (pic, info)) in pick(&pictures, order).into_iter().enumerate() {
+				tag.set_picture(i, pic, info);
+			}
 		}
 	}
+}
+
+/// A picture independent of the tag format, for copying between files
+#[derive(Clone, Serialize)]
+pub struct Picture {
+	pub picture_type: u8,
+	pub description: String,
+	pub mime_type: String,
+	#[serde(skip)]
+	pub data: Vec<u8>,
+}
+
+pub fn get_pictures(metadata: &Metadata) -> Vec<Picture> {
+	let mut pictures = Vec::new();
+	for index in 0..image_count(metadata) {
+		let (data, mime_type) = match get_image_data(metadata, index) {
+			Some(image) => image,
+			None => continue,
+		};
+		let description = match metadata {
+			Metadata::Id3(tag) => tag.pictures().nth(index).map(|pic| pic.description.clone()),
+			Metadata::Mp4(_) => None,
+			Metadata::VorbisComments(tag) => tag
+				.pictures()
+				.get(index)
+				.and_then(|(pic, _info)| pic.description().map(|s| s.to_string())),
+		};
+		pictures.push(Picture {
+			// MP4 artwork has no type, but the first one is shown as the cover
+			picture_type: match picture_type_id(metadata, index) {
+				Some(id) => id,
+				None if index == 0 => 3,
+				None => 0,
+			},
+			description: description.unwrap_or_default(),
+			mime_type,
+			data: data.to_vec(),
+		});
+	}
+	pictures
+}
+
+/// Replace all pictures. Pictures in formats the tag can't store are
+/// converted to PNG. The tag is left unchanged if any picture fails.
+pub fn replace_pictures(metadata: &mut Metadata, pictures: Vec<Picture>) -> Result<(), Error> {
+	let mut converted = Vec::new();
+	for picture in pictures {
+		let data = if artwork::supports(metadata, &picture.mime_type) {
+			picture.data
+		} else {
+			artwork::convert(&picture.data, "image/png")?
+		};
+		converted.push((data, picture.picture_type, picture.description));
+	}
+	let mut new_metadata = metadata.clone();
+	rearrange_images(&mut new_metadata, &[]);
+	for (index, (data, picture_type, description)) in converted.into_iter().enumerate() {
+		put_image(&mut new_metadata, index, data)?;
+		if let Metadata::Id3(_) | Metadata::VorbisComments(_) = new_metadata {
+			set_info(&mut new_metadata, index, picture_type, Some(description))?;
+		}
+	}
+	*metadata = new_metadata;
+	Ok(())
+}
+
+#[command]
+pub fn remove_image(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let count = image_count(&file.metadata);
+	if index >= count {
+		throw!(IndexOutOfRange: "Index out of range");
+	}
+	let order: Vec<_> = (0..count).filter(|i| *i != index).collect();
+	rearrange_images(&mut file.metadata, &order);
 	file.dirty = true;
 	Ok(())
 }
 
-enum MimeType {
-	Png,
-	Jpeg,
+#[command]
+pub fn move_image(from: usize, to: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let count = image_count(&file.metadata);
+	if from >= count || to >= count {
+		throw!(IndexOutOfRange: "Index out of range");
+	}
+	let mut order: Vec<_> = (0..count).collect();
+	let index = order.remove(from);
+	order.insert(to, index);
+	rearrange_images(&mut file.metadata, &order);
+	file.dirty = true;
+	Ok(())
+}
+
+#[derive(Serialize)]
+pub struct DuplicateImage {
+	index: usize,
+	/// Index of the first image this is a duplicate of
+	duplicate_of: usize,
+	/// Whether the bytes are identical, as opposed to only looking the same
+	identical: bool,
+	/// Identical bytes, picture type and description, so it can be removed
+	/// without asking. Other duplicates need to be confirmed by the user.
+	removable: bool,
+}
+
+fn find_duplicates(metadata: &Metadata) -> Vec<DuplicateImage> {
+	let pictures = get_pictures(metadata);
+	let hashes: Vec<_> = pictures
+		.iter()
+		.map(|picture| artwork::perceptual_hash(&picture.data))
+		.collect();
+	let mut duplicates = Vec::new();
+	for (index, picture) in pictures.iter().enumerate() {
+		let original = pictures[..index]
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| !duplicates.iter().any(|d: &DuplicateImage| d.index == *i))
+			.find_map(|(i, other)| {
+				if picture.data == other.data {
+					Some((i, true))
+				} else if artwork::looks_identical(hashes[index], hashes[i]) {
+					Some((i, false))
+				} else {
+					None
+				}
+			});
+		if let Some((duplicate_of, identical)) = original {
+			let other = &pictures[duplicate_of];
+			duplicates.push(DuplicateImage {
+				index,
+				duplicate_of,
+				identical,
+				removable: identical
+					&& picture.picture_type == other.picture_type
+					&& picture.description == other.description,
+			});
+		}
+	}
+	duplicates
 }
 
+/// Find images that are the same as an earlier image in the current file,
+/// either byte for byte or visually. Visual matches can be different pictures,
+/// like a similar back cover or a dark cover, so they are only listed.
 #[command]
-pub fn set_image(index: usize, path: PathBuf, app: AppArg<'_>) -> Result<(), String> {
-	let mut app = app.0.lock().unwrap();
-	let file = app.current_file().unwrap();
-	let new_bytes = match fs::read(&path) {
+pub fn get_duplicate_images(app: AppArg<'_>) -> Result<Vec<DuplicateImage>, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	Ok(find_duplicates(&file.metadata))
+}
+
+/// Remove the removable duplicate images from the current file, keeping the
+/// first of each. Returns the number of images removed.
+#[command]
+pub fn remove_duplicate_images(app: AppArg<'_>) -> Result<usize, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let duplicates: Vec<_> = find_duplicates(&file.metadata)
+		.into_iter()
+		.filter(|d| d.removable)
+		.collect();
+	if duplicates.is_empty() {
+		return Ok(0);
+	}
+	let order: Vec<_> = (0..image_count(&file.metadata))
+		.filter(|i| !duplicates.iter().any(|d| d.index == *i))
+		.collect();
+	rearrange_images(&mut file.metadata, &order);
+	file.dirty = true;
+	Ok(duplicates.len())
+}
+
+#[command]
+pub fn set_image(
+	index: usize,
+	path: PathBuf,
+	options: Option<ProcessOptions>,
+	convert_to: Option<String>,
+	app: AppArg<'_>,
+) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let mut new_bytes = match fs::read(&path) {
 		Ok(b) => b,
-		Err(e) => throw!("Error reading that file: {}", e),
-	};
-	let ext = path.extension().unwrap_or_default().to_string_lossy();
-	let mime_type = match ext.as_ref() {
-		"jpg" | "jpeg" => MimeType::Jpeg,
-		"png" => MimeType::Png,
-		ext => throw!("Unsupported file type: {}", ext),
+		Err(e) => return Err(Error::io("Error reading that file", &path, e)),
 	};
-	match file.metadata {
-		Metadata::Id3(ref mut tag) => {
+	if let Some(options) = options {
+		if let Some(processed) = artwork::process(&new_bytes, &options)? {
+			new_bytes = processed;
+		}
+	}
+	let mime_type = artwork::detect_mime_type(&new_bytes)?;
+	if !artwork::supports(&file.metadata, mime_type) {
+		if let Some(convert_to) = convert_to {
+			new_bytes = artwork::convert(&new_bytes, &convert_to)?;
+		}
+	}
+	put_image(&mut file.metadata, index, new_bytes)?;
+	file.dirty = true;
+	Ok(())
+}
+
+/// Replace the image at `index`, keeping its picture type and description, or
+/// add a new image if `index` is the number of images. The format is detected
+/// from the image data.
+pub fn put_image(metadata: &mut Metadata, index: usize, new_bytes: Vec<u8>) -> Result<(), Error> {
+	let mime_type = artwork::detect_mime_type(&new_bytes)?;
+	if !artwork::supports(metadata, mime_type) {
+		throw!(UnsupportedFormat: "{} images can't be stored in this file type", mime_type);
+	}
+	match metadata {
+		Metadata::Id3(tag) => {
 			let mut pic_frames: Vec<_> = tag
 				.frames()
 				.filter(|frame| frame.content().picture().is_some())
 				.map(|frame| frame.clone())
 				.collect();
-			let mime_type = match ext.as_ref() {
-				"jpg" | "jpeg" => "image/jpeg".to_string(),
-				"png" => "image/png".to_string(),
-				ext => throw!("Unsupported file type: {}", ext),
-			};
 			let mut new_pic = id3::frame::Picture {
-				mime_type,
-				picture_type: id3::frame::PictureType::Other,
+				mime_type: mime_type.to_string(),
+				picture_type: if pic_frames.is_empty() {
+					id3::frame::PictureType::CoverFront
+				} else {
+					id3::frame::PictureType::Other
+				},
 				description: "".to_string(),
 				data: new_bytes,
 			};
@@ -208,23 +513,19 @@ pub fn set_image(index: usize, path: PathBuf, app: AppArg<'_>) -> Result<(), Str
 							id3::Frame::with_content("APIC", id3::Content::Picture(new_pic));
 						pic_frames.insert(index, new_frame);
 					} else {
-						throw!("Index out of range");
+						throw!(IndexOutOfRange: "Index out of range");
 					}
 				}
 			}
-			tag.remove_all_pictures();
-			for pic_frame in pic_frames {
-				tag.add_frame(pic_frame);
-			}
+			set_id3_pictures(tag, pic_frames);
 		}
-		Metadata::Mp4(ref mut tag) => {
+		Metadata::Mp4(tag) => {
 			let mut artworks: Vec<_> = tag.take_artworks().collect();
 			let new_artwork = mp4ameta::Img {
-				fmt: match ext.as_ref() {
-					"jpg" | "jpeg" => mp4ameta::ImgFmt::Jpeg,
-					"png" => mp4ameta::ImgFmt::Png,
-					"bmp" => mp4ameta::ImgFmt::Bmp,
-					ext => throw!("Unsupported file type: {}", ext),
+				fmt: match mime_type {
+					"image/png" => mp4ameta::ImgFmt::Png,
+					"image/bmp" => mp4ameta::ImgFmt::Bmp,
+					_ => mp4ameta::ImgFmt::Jpeg,
 				},
 				data: new_bytes,
 			};
@@ -236,37 +537,321 @@ pub fn set_image(index: usize, path: PathBuf, app: AppArg<'_>) -> Result<(), Str
 					if index == artworks.len() {
 						artworks.push(new_artwork);
 					} else {
-						throw!("Index out of range");
+						throw!(IndexOutOfRange: "Index out of range");
 					}
 				}
 			}
 			tag.set_artworks(artworks);
 		}
-		Metadata::VorbisComments(ref mut tag) => {
+		Metadata::VorbisComments(tag) => {
 			if index <= tag.pictures().len() {
-				let info_result = match mime_type {
-					MimeType::Png => lofty::PictureInformation::from_png(&new_bytes),
-					MimeType::Jpeg => lofty::PictureInformation::from_jpeg(&new_bytes),
+				let info = match mime_type {
+					"image/png" => lofty::PictureInformation::from_png(&new_bytes),
+					"image/jpeg" => lofty::PictureInformation::from_jpeg(&new_bytes),
+					// lofty can only read PNG and JPEG info
+					_ => {
+						let (width, height) = artwork::get_dimensions(&new_bytes).unwrap_or((0, 0));
+						Ok(lofty::PictureInformation {
+							width,
+							height,
+							color_depth: 0,
+							num_colors: 0,
+						})
+					}
 				};
-				let info = match info_result {
+				let info = match info {
 					Ok(info) => info,
-					Err(e) => throw!("Error reading picture info: {}", e),
+					Err(e) => throw!(Parse: "Error reading picture info: {}", e),
 				};
-				let mut byte_cursor = std::io::Cursor::new(new_bytes);
-				let pic = match lofty::Picture::from_reader(&mut byte_cursor) {
-					Ok(mut pic) => {
-						pic.set_pic_type(lofty::PictureType::Other);
-						pic
+				let mut pic = lofty::Picture::new_unchecked(
+					lofty::PictureType::Other,
+					lofty::MimeType::from_str(mime_type),
+					None,
+					new_bytes,
+				);
+				match tag.pictures().get(index) {
+					Some((old_pic, _info)) => {
+						pic.set_pic_type(old_pic.pic_type());
+						pic.set_description(old_pic.description().map(|s| s.to_string()));
 					}
-					Err(e) => throw!("Error reading picture: {}", e),
-				};
+					None if tag.pictures().is_empty() => {
+						pic.set_pic_type(lofty::PictureType::CoverFront)
+					}
+					None => pic.set_pic_type(lofty::PictureType::Other),
+				}
 				// this is safe because set_picture appends if out of bounds:
 				tag.set_picture(index, pic, info);
 			} else {
-				throw!("Index out of range");
+				throw!(IndexOutOfRange: "Index out of range");
 			}
 		}
 	}
+	Ok(())
+}
+
+/// Set the picture type (the APIC type number) and description of an image
+#[command]
+pub fn set_image_info(
+	index: usize,
+	picture_type: u8,
+	description: String,
+	app: AppArg<'_>,
+) -> Result<(), Error> {
+	if picture_type > 20 {
+		throw!(InvalidInput: "Invalid picture type {}", picture_type);
+	}
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	set_info(&mut file.metadata, index, picture_type, Some(description))?;
 	file.dirty = true;
 	Ok(())
 }
+
+/// Set the picture type of an image, and the description unless it's None
+fn set_info(
+	metadata: &mut Metadata,
+	index: usize,
+	picture_type: u8,
+	description: Option<String>,
+) -> Result<(), Error> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let mut pic_frames: Vec<_> = tag
+				.frames()
+				.filter(|frame| frame.content().picture().is_some())
+				.map(|frame| frame.clone())
+				.collect();
+			let old_frame = match pic_frames.get_mut(index) {
+				Some(old_frame) => old_frame,
+				None => throw!(IndexOutOfRange: "Index out of range"),
+			};
+			let mut new_pic = old_frame.content().picture().unwrap().clone();
+			new_pic.picture_type = id3_picture_type(picture_type);
+			if let Some(description) = description {
+				new_pic.description = description;
+			}
+			*old_frame = id3::Frame::with_content("APIC", id3::Content::Picture(new_pic));
+			set_id3_pictures(tag, pic_frames);
+		}
+		Metadata::Mp4(_) => {
+			throw!(UnsupportedFormat: "MP4 artwork has no picture type or description")
+		}
+		Metadata::VorbisComments(tag) => {
+			let (mut pic, info) = match tag.pictures().get(index) {
+				Some(pic) => pic.clone(),
+				None => throw!(IndexOutOfRange: "Index out of range"),
+			};
+			pic.set_pic_type(lofty::PictureType::from_u8(picture_type));
+			if let Some(description) = description {
+				pic.set_description(match description.as_ref() {
+					"" => None,
+					_ => Some(description),
+				});
+			}
+			tag.set_picture(index, pic, info);
+		}
+	}
+	Ok(())
+}
+
+/// Write the image at `index` to `path`, with the extension replaced to match
+/// the image format. Returns the path that was written.
+#[command]
+pub fn export_image(index: usize, path: PathBuf, app: AppArg<'_>) -> Result<PathBuf, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let (data, mime_type) = match get_image_data(&file.metadata, index) {
+		Some(image) => image,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	let mut path = path;
+	match extension_for(&mime_type) {
+		Some(ext) => path.set_extension(ext),
+		None => throw!(UnsupportedFormat: "Unknown image type {}", mime_type),
+	};
+	match fs::write(&path, data) {
+		Ok(_) => Ok(path),
+		Err(e) => return Err(Error::io("Error writing file", &path, e)),
+	}
+}
+
+#[derive(Default, Serialize)]
+pub struct CoverExportReport {
+	written: Vec<PathBuf>,
+	/// Folders skipped because the cover file already exists
+	existing: Vec<PathBuf>,
+	/// Folders where no open file has artwork
+	no_artwork: Vec<PathBuf>,
+	/// Covers that couldn't be written
+	failed: Vec<FileError>,
+}
+
+/// Save the front cover of the open files as `<file_stem>.jpg` (or the
+/// matching extension) in each album folder. The first open file with artwork
+/// in a folder is used. `file_stem` must be a plain file name, so covers
+/// can't end up outside the album folders.
+#[command]
+pub fn export_front_covers(
+	file_stem: String,
+	overwrite: bool,
+	app: AppArg<'_>,
+) -> Result<CoverExportReport, Error> {
+	let mut components = Path::new(&file_stem).components();
+	match (components.next(), components.next()) {
+		(Some(Component::Normal(_)), None) => {}
+		_ => throw!(InvalidInput: "Invalid cover file name \"{}\"", file_stem),
+	}
+	let app = app.lock()?;
+	let mut report = CoverExportReport::default();
+	let mut dirs: Vec<PathBuf> = Vec::new();
+	for file in &app.files {
+		if let Some(dir) = file.path.parent() {
+			if !dirs.iter().any(|d| d == dir) {
+				dirs.push(dir.to_path_buf());
+			}
+		}
+	}
+	for dir in dirs {
+		let cover = app
+			.files
+			.iter()
+			.filter(|file| file.path.parent() == Some(&dir))
+			.find_map(|file| {
+				let index = default_index(&file.metadata)?;
+				get_image_data(&file.metadata, index)
+			});
+		let (data, mime_type) = match cover {
+			Some(cover) => cover,
+			None => {
+				report.no_artwork.push(dir);
+				continue;
+			}
+		};
+		let ext = match extension_for(&mime_type) {
+			Some(ext) => ext,
+			None => {
+				let error = Error::new(
+					ErrorKind::UnsupportedFormat,
+					format!("Unknown image type {}", mime_type),
+				);
+				report.failed.push(FileError::new(dir, error));
+				continue;
+			}
+		};
+		let path = dir.join(format!("{}.{}", file_stem, ext));
+		if path.exists() && !overwrite {
+			report.existing.push(dir);
+			continue;
+		}
+		match fs::write(&path, data) {
+			Ok(_) => report.written.push(path),
+			Err(e) => {
+				let error = Error::io("Error writing file", &path, e);
+				report.failed.push(FileError::new(path, error));
+			}
+		}
+	}
+	Ok(report)
+}
+
+/// Cover file names in order of preference
+const SIDECAR_NAMES: [&str; 3] = ["cover", "folder", "front"];
+const SIDECAR_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+fn find_sidecar(dir: &Path) -> Option<PathBuf> {
+	let entries: Vec<_> = fs::read_dir(dir)
+		.ok()?
+		.flatten()
+		.map(|e| e.path())
+		.collect();
+	for name in SIDECAR_NAMES {
+		for path in &entries {
+			let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+			let ext = path.extension().unwrap_or_default().to_string_lossy();
+			if stem.eq_ignore_ascii_case(name)
+				&& SIDECAR_EXTENSIONS
+					.iter()
+					.any(|e| ext.eq_ignore_ascii_case(e))
+				&& path.is_file()
+			{
+				return Some(path.clone());
+			}
+		}
+	}
+	None
+}
+
+fn has_image_data(metadata: &Metadata, data: &[u8]) -> bool {
+	let mut index = 0;
+	while let Some((image_data, _mime_type)) = get_image_data(metadata, index) {
+		if image_data == data {
+			return true;
+		}
+		index += 1;
+	}
+	false
+}
+
+#[derive(Default, Serialize)]
+pub struct SidecarImportReport {
+	/// Cover files that were found
+	covers: Vec<PathBuf>,
+	/// Number of files the cover was embedded in
+	updated: usize,
+	/// Number of files that already had the cover
+	unchanged: usize,
+}
+
+/// Look for cover images like cover.jpg or folder.png next to the open files,
+/// and embed them as the front cover of every open file in the same folder.
+/// Files that already contain the same image are skipped.
+#[command]
+pub fn import_sidecar_covers(app: AppArg<'_>) -> Result<SidecarImportReport, Error> {
+	let mut app = app.lock()?;
+	let mut report = SidecarImportReport::default();
+	let mut dirs: Vec<PathBuf> = Vec::new();
+	for file in &app.files {
+		if let Some(dir) = file.path.parent() {
+			if !dirs.iter().any(|d| d == dir) {
+				dirs.push(dir.to_path_buf());
+			}
+		}
+	}
+	for dir in dirs {
+		let cover_path = match find_sidecar(&dir) {
+			Some(path) => path,
+			None => continue,
+		};
+		let data = match fs::read(&cover_path) {
+			Ok(b) => b,
+			Err(e) => return Err(Error::io("Error reading file", &cover_path, e)),
+		};
+		for file in &mut app.files {
+			if file.path.parent() != Some(&dir) {
+				continue;
+			}
+			if has_image_data(&file.metadata, &data) {
+				report.unchanged += 1;
+				continue;
+			}
+			let front_cover = match file.metadata {
+				// MP4 artwork has no type, and players show the first one
+				Metadata::Mp4(_) => Some(0),
+				_ => default_index(&file.metadata)
+					.filter(|i| picture_type_id(&file.metadata, *i) == Some(3)),
+			};
+			let index = match front_cover {
+				Some(index) => index,
+				None => image_count(&file.metadata),
+			};
+			put_image(&mut file.metadata, index, data.clone())?;
+			if let Metadata::Id3(_) | Metadata::VorbisComments(_) = file.metadata {
+				set_info(&mut file.metadata, index, 3, None)?;
+			}
+			file.dirty = true;
+			report.updated += 1;
+		}
+		report.covers.push(cover_path);
+	}
+	Ok(report)
+}
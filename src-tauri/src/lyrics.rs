@@ -0,0 +1,418 @@
+use crate::cmd::AppArg;
+use crate::error::Error;
+use crate::frames::Metadata;
+use crate::throw;
+use id3::TagLike;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tauri::command;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lyrics {
+	pub text: String,
+	pub lang: Option<String>,
+	pub description: Option<String>,
+	/// The Vorbis comment key, so `UNSYNCEDLYRICS` isn't rewritten as `LYRICS`
+	#[serde(default)]
+	pub key: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SyncedLine {
+	pub time_ms: u32,
+	pub text: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct SyncedLyrics {
+	pub lang: Option<String>,
+	pub description: Option<String>,
+	pub lines: Vec<SyncedLine>,
+}
+
+#[derive(Serialize)]
+pub struct LyricsPage {
+	unsynced: Vec<Lyrics>,
+	synced: Vec<SyncedLyrics>,
+}
+
+/// Most players read `LYRICS`, but foobar2000 uses `UNSYNCEDLYRICS`
+const VORBIS_KEYS: [&str; 2] = ["LYRICS", "UNSYNCEDLYRICS"];
+
+fn parse_timestamp(s: &str) -> Option<u32> {
+	let (min, rest) = s.split_once(':')?;
+	let (sec, frac) = match rest.split_once('.').or_else(|| rest.split_once(':')) {
+		Some((sec, frac)) => (sec, frac),
+		None => (rest, ""),
+	};
+	let min: u32 = min.trim().parse().ok()?;
+	let sec: u32 = sec.trim().parse().ok()?;
+	// "xx" is hundredths and "xxx" is thousandths
+	let frac_ms = match frac.len() {
+		0 => 0,
+		1 => frac.parse::<u32>().ok()? * 100,
+		2 => frac.parse::<u32>().ok()? * 10,
+		_ => frac.get(..3)?.parse::<u32>().ok()?,
+	};
+	min.checked_mul(60_000)?
+		.checked_add(sec.checked_mul(1000)?)?
+		.checked_add(frac_ms)
+}
+
+fn format_timestamp(ms: u32) -> String {
+	format!(
+		"{:02}:{:02}.{:02}",
+		ms / 60_000,
+		ms / 1000 % 60,
+		ms % 1000 / 10
+	)
+}
+
+/// Parse LRC text into lines sorted by time. Lines with several timestamps
+/// are repeated for each of them, and `[offset:]` is applied.
+pub fn parse_lrc(text: &str) -> Vec<SyncedLine> {
+	let mut lines = Vec::new();
+	let mut offset: i64 = 0;
+	for line in text.lines() {
+		let mut rest = line.trim();
+		let mut times = Vec::new();
+		while rest.starts_with('[') {
+			let end = match rest.find(']') {
+				Some(end) => end,
+				None => break,
+			};
+			let tag = &rest[1..end];
+			if let Some(time) = parse_timestamp(tag) {
+				times.push(time);
+			} else if let Some(value) = tag.strip_prefix("offset:") {
+				offset = value.trim().parse().unwrap_or(0);
+			}
+			rest = &rest[end + 1..];
+		}
+		for time in times {
+			// a positive offset means the lyrics should show up sooner
+			let time = (time as i64)
+				.saturating_sub(offset)
+				.clamp(0, u32::MAX as i64) as u32;
+			lines.push(SyncedLine {
+				time_ms: time,
+				text: rest.trim().to_string(),
+			});
+		}
+	}
+	lines.sort_by_key(|line| line.time_ms);
+	lines
+}
+
+pub fn to_lrc(lines: &[SyncedLine]) -> String {
+	let mut lrc = String::new();
+	for line in lines {
+		lrc.push_str(&format!(
+			"[{}]{}\n",
+			format_timestamp(line.time_ms),
+			line.text
+		));
+	}
+	lrc
+}
+
+fn to_id3_lang(lang: Option<String>) -> String {
+	match lang {
+		Some(lang) if lang.len() == 3 => lang,
+		_ => "eng".to_string(),
+	}
+}
+
+pub fn get_unsynced(metadata: &Metadata) -> Vec<Lyrics> {
+	match metadata {
+		Metadata::Id3(tag) => tag
+			.lyrics()
+			.map(|l| Lyrics {
+				text: l.text.clone(),
+				lang: Some(l.lang.clone()),
+				description: Some(l.description.clone()),
+				key: None,
+			})
+			.collect(),
+		Metadata::Mp4(tag) => tag
+			.lyrics()
+			.map(|text| Lyrics {
+				text: text.to_string(),
+				lang: None,
+				description: None,
+				key: None,
+			})
+			.into_iter()
+			.collect(),
+		Metadata::VorbisComments(tag) => VORBIS_KEYS
+			.iter()
+			.flat_map(|key| tag.get_all(key).map(move |text| (key, text)))
+			.map(|(key, text)| Lyrics {
+				text: text.to_string(),
+				lang: None,
+				description: None,
+				key: Some(key.to_string()),
+			})
+			.collect(),
+	}
+}
+
+fn get_synced(metadata: &Metadata) -> Vec<SyncedLyrics> {
+	match metadata {
+		Metadata::Id3(tag) => tag
+			.synchronised_lyrics()
+			// MPEG frame timestamps can't be converted without decoding the audio
+			.filter(|l| l.timestamp_format == id3::frame::TimestampFormat::Ms)
+			.map(|l| SyncedLyrics {
+				lang: Some(l.lang.clone()),
+				description: Some(l.description.clone()),
+				lines: l
+					.content
+					.iter()
+					.map(|(time_ms, text)| SyncedLine {
+						time_ms: *time_ms,
+						text: text.clone(),
+					})
+					.collect(),
+			})
+			.collect(),
+		// Other formats have no synced lyrics field, so LRC text is stored as
+		// regular lyrics
+		_ => get_unsynced(metadata)
+			.into_iter()
+			.filter_map(|lyrics| {
+				let lines = parse_lrc(&lyrics.text);
+				if lines.is_empty() {
+					return None;
+				}
+				Some(SyncedLyrics {
+					lang: lyrics.lang,
+					description: lyrics.description,
+					lines,
+				})
+			})
+			.collect(),
+	}
+}
+
+fn set_unsynced(metadata: &mut Metadata, index: usize, lyrics: Lyrics) -> Result<(), Error> {
+	let mut all = get_unsynced(metadata);
+	match all.get_mut(index) {
+		Some(old) => *old = lyrics,
+		None => {
+			if index == all.len() {
+				all.push(lyrics);
+			} else {
+				throw!(IndexOutOfRange: "Index out of range");
+			}
+		}
+	}
+	replace_unsynced(metadata, all)
+}
+
+/// Replace all unsynced lyrics. ID3 lyrics need a unique language and
+/// description, or one would replace the other.
+pub fn replace_unsynced(metadata: &mut Metadata, all: Vec<Lyrics>) -> Result<(), Error> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let mut contents: Vec<id3::frame::Lyrics> = Vec::new();
+			for lyrics in all {
+				let content = id3::frame::Lyrics {
+					lang: to_id3_lang(lyrics.lang),
+					description: lyrics.description.unwrap_or_default(),
+					text: lyrics.text,
+				};
+				let same_key = |l: &id3::frame::Lyrics| {
+					l.lang == content.lang && l.description == content.description
+				};
+				if contents.iter().any(same_key) {
+					throw!(
+						Conflict:
+						"Other lyrics have the same language ({}) and description (\"{}\")",
+						content.lang,
+						content.description
+					);
+				}
+				contents.push(content);
+			}
+			tag.remove("USLT");
+			for content in contents {
+				tag.add_frame(id3::Frame::with_content(
+					"USLT",
+					id3::Content::Lyrics(content),
+				));
+			}
+		}
+		Metadata::Mp4(tag) => match all.len() {
+			0 => tag.remove_lyrics(),
+			1 => tag.set_lyrics(all.into_iter().next().unwrap().text),
+			_ => throw!(UnsupportedFormat: "MP4 files only support one lyrics field"),
+		},
+		Metadata::VorbisComments(tag) => {
+			for key in VORBIS_KEYS {
+				let _ = tag.remove(key);
+			}
+			for lyrics in all {
+				let key = match lyrics.key {
+					Some(key) if VORBIS_KEYS.contains(&key.as_str()) => key,
+					_ => VORBIS_KEYS[0].to_string(),
+				};
+				tag.push(key, lyrics.text);
+			}
+		}
+	}
+	Ok(())
+}
+
+#[command]
+pub fn get_lyrics(app: AppArg<'_>) -> Result<LyricsPage, Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	Ok(LyricsPage {
+		unsynced: get_unsynced(&file.metadata),
+		synced: get_synced(&file.metadata),
+	})
+}
+
+#[command]
+pub fn set_lyrics(index: usize, lyrics: Lyrics, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	set_unsynced(&mut file.metadata, index, lyrics)?;
+	file.dirty = true;
+	Ok(())
+}
+
+#[command]
+pub fn remove_lyrics(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let mut all = get_unsynced(&file.metadata);
+	if index >= all.len() {
+		throw!(IndexOutOfRange: "Index out of range");
+	}
+	all.remove(index);
+	replace_unsynced(&mut file.metadata, all)?;
+	file.dirty = true;
+	Ok(())
+}
+
+#[command]
+pub fn remove_synced_lyrics(index: usize, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	match file.metadata {
+		Metadata::Id3(ref mut tag) => {
+			let mut sylt_frames: Vec<_> = tag
+				.frames()
+				.filter(|frame| frame.content().synchronised_lyrics().is_some())
+				.map(|frame| frame.clone())
+				.collect();
+			// indexes match get_synced(), which skips MPEG frame timestamps
+			let frame_index = sylt_frames
+				.iter()
+				.enumerate()
+				.filter_map(|(i, frame)| {
+					let lyrics = frame.content().synchronised_lyrics()?;
+					match lyrics.timestamp_format {
+						id3::frame::TimestampFormat::Ms => Some(i),
+						_ => None,
+					}
+				})
+				.nth(index);
+			match frame_index {
+				Some(i) => sylt_frames.remove(i),
+				None => throw!(IndexOutOfRange: "Index out of range"),
+			};
+			tag.remove("SYLT");
+			for sylt_frame in sylt_frames {
+				tag.add_frame(sylt_frame);
+			}
+		}
+		_ => {
+			// indexes match get_synced(), which lists the lyrics that parse as LRC
+			let mut all = get_unsynced(&file.metadata);
+			let lyrics_index = all
+				.iter()
+				.enumerate()
+				.filter(|(_, lyrics)| !parse_lrc(&lyrics.text).is_empty())
+				.map(|(i, _)| i)
+				.nth(index);
+			match lyrics_index {
+				Some(i) => all.remove(i),
+				None => throw!(IndexOutOfRange: "Index out of range"),
+			};
+			replace_unsynced(&mut file.metadata, all)?;
+		}
+	}
+	file.dirty = true;
+	Ok(())
+}
+
+/// Import an LRC file. ID3 gets a SYLT frame, while other formats store the LRC
+/// text as lyrics so the timing is kept. The lyrics are added next to existing
+/// ones, except in MP4 which only has one lyrics field, and in ID3 which only
+/// has one SYLT frame per language and type. Existing lyrics are only replaced
+/// if `replace` is set.
+#[command]
+pub fn import_lrc(path: PathBuf, replace: bool, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(e) => return Err(Error::io("Error reading that file", &path, e)),
+	};
+	let lines = parse_lrc(&text);
+	if lines.is_empty() {
+		throw!(Parse: "No timestamped lines found in {}", path.to_string_lossy());
+	}
+	match file.metadata {
+		Metadata::Id3(ref mut tag) => {
+			let content = id3::frame::SynchronisedLyrics {
+				lang: "eng".to_string(),
+				timestamp_format: id3::frame::TimestampFormat::Ms,
+				content_type: id3::frame::SynchronisedLyricsType::Lyrics,
+				description: "".to_string(),
+				content: lines.into_iter().map(|l| (l.time_ms, l.text)).collect(),
+			};
+			let exists = tag.synchronised_lyrics().any(|lyrics| {
+				lyrics.lang == content.lang && lyrics.content_type == content.content_type
+			});
+			if exists && !replace {
+				throw!(Conflict: "This file already has synced English lyrics");
+			}
+			tag.add_frame(id3::Frame::with_content(
+				"SYLT",
+				id3::Content::SynchronisedLyrics(content),
+			));
+		}
+		Metadata::Mp4(ref mut tag) => {
+			if tag.lyrics().is_some() && !replace {
+				throw!(Conflict: "This file already has lyrics, and MP4 files only support one lyrics field");
+			}
+			tag.set_lyrics(to_lrc(&lines));
+		}
+		Metadata::VorbisComments(ref mut tag) => {
+			tag.push(VORBIS_KEYS[0].to_string(), to_lrc(&lines));
+		}
+	}
+	file.dirty = true;
+	Ok(())
+}
+
+/// Export synced lyrics at `index` as an LRC file
+#[command]
+pub fn export_lrc(index: usize, path: PathBuf, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let file = app.current_file()?;
+	let synced = get_synced(&file.metadata);
+	let lyrics = match synced.get(index) {
+		Some(lyrics) => lyrics,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	match fs::write(&path, to_lrc(&lyrics.lines)) {
+		Ok(_) => Ok(()),
+		Err(e) => return Err(Error::io("Error writing file", &path, e)),
+	}
+}
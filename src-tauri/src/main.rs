@@ -11,15 +11,33 @@ use tauri::{
 	WindowBuilder, WindowUrl,
 };
 
+mod artwork;
+mod chapters;
 mod cmd;
+mod comments;
+mod diff;
+mod error;
+mod fields;
 mod files;
 mod frames;
 mod image;
+mod lyrics;
+mod protocol;
+mod rating;
+mod save;
+mod spreadsheet;
+mod transfer;
+mod watch;
 
+/// Return an error. Prefix the message with an `ErrorKind` variant, like
+/// `throw!(Parse: "...")`, to set the kind
 #[macro_export]
 macro_rules! throw {
+  ($kind:ident: $($arg:tt)*) => {{
+    return Err($crate::error::Error::new($crate::error::ErrorKind::$kind, format!($($arg)*)).into())
+  }};
   ($($arg:tt)*) => {{
-    return Err(format!($($arg)*))
+    return Err(format!($($arg)*).into())
   }};
 }
 
@@ -32,12 +50,55 @@ fn main() {
 			cmd::show,
 			cmd::close_window,
 			cmd::get_page,
+			artwork::get_image_file_info,
+			artwork::normalize_all_artwork,
+			chapters::get_chapters,
+			chapters::get_chapter_image,
+			chapters::set_chapter,
+			chapters::move_chapter,
+			chapters::remove_chapter,
+			chapters::set_chapter_image,
+			chapters::import_chapters,
+			chapters::export_chapters,
+			comments::set_comment,
+			comments::remove_comment,
+			diff::get_diff,
+			diff::revert_change,
+			fields::copy_tags,
+			fields::paste_tags,
 			files::open_files,
+			files::cancel_open,
 			files::close_file,
 			files::save_file,
+			files::save_all,
+			files::save_copies,
+			files::revert_file,
 			image::get_image,
 			image::remove_image,
+			image::move_image,
+			image::get_duplicate_images,
+			image::remove_duplicate_images,
 			image::set_image,
+			image::set_image_info,
+			image::export_image,
+			image::export_front_covers,
+			image::import_sidecar_covers,
+			lyrics::get_lyrics,
+			lyrics::set_lyrics,
+			lyrics::remove_lyrics,
+			lyrics::remove_synced_lyrics,
+			lyrics::import_lrc,
+			lyrics::export_lrc,
+			rating::set_rating,
+			rating::set_rating_play_count,
+			rating::set_popm_email,
+			save::set_backup_policy,
+			save::set_preserve_times,
+			spreadsheet::export_tag_table,
+			spreadsheet::preview_tag_import,
+			spreadsheet::apply_tag_import,
+			transfer::transfer_tags,
+			watch::get_external_changes,
 		])
 		.setup(|app| {
 			let _ = WindowBuilder::new(app, "main", WindowUrl::default())
@@ -50,9 +111,21 @@ fn main() {
 				.skip_taskbar(false)
 				.build()
 				.expect("Unable to create window");
+			let save_options = save::load_options(&app.handle());
+			if let Ok(mut app) = app.state::<cmd::AppState>().lock() {
+				if let Some(email) = &save_options.popm_email {
+					app.popm_email = email.clone();
+				}
+				app.save_options = save_options;
+			}
+			watch::start(&app.handle());
 			Ok(())
 		})
 		.manage(cmd::AppState(Default::default()))
+		.manage(protocol::ThumbnailCache::default())
+		.manage(files::OpenState::default())
+		.manage(watch::FileWatcher::default())
+		.register_uri_scheme_protocol("art", protocol::handle_art_request)
 		.menu(Menu::with_items([
 			#[cfg(target_os = "macos")]
 			MenuEntry::Submenu(Submenu::new(
@@ -86,6 +159,16 @@ fn main() {
 					CustomMenuItem::new("Save As...", "Save As...")
 						.accelerator("shift+cmdOrControl+S")
 						.into(),
+					CustomMenuItem::new("Save a Copy...", "Save a Copy...").into(),
+					CustomMenuItem::new("Save All", "Save All")
+						.accelerator("alt+cmdOrControl+S")
+						.into(),
+					CustomMenuItem::new("Save Copies to Folder...", "Save Copies to Folder...")
+						.into(),
+					MenuItem::Separator.into(),
+					CustomMenuItem::new("Revert", "Revert to Saved")
+						.accelerator("cmdOrControl+R")
+						.into(),
 				]),
 			)),
 			MenuEntry::Submenu(Submenu::new(
@@ -119,11 +202,11 @@ fn main() {
 		]))
 		.on_menu_event(|event| {
 			let event_name = event.menu_item_id();
-			event.window().emit("menu", event_name).unwrap();
+			let _ = event.window().emit("menu", event_name);
 			match event_name {
 				"Learn More" => {
 					let link = "https://github.com/probablykasper/mr-tagger".to_string();
-					shell::open(&event.window().shell_scope(), link, None).unwrap();
+					let _ = shell::open(&event.window().shell_scope(), link, None);
 				}
 				_ => {}
 			}
@@ -146,13 +229,11 @@ fn main() {
 
 fn is_dirty(app: &AppHandle) -> bool {
 	let app: AppArg<'_> = app.state();
-	let app = app.0.lock().unwrap();
-	for file in &app.files {
-		if file.dirty {
-			return true;
-		}
+	match app.lock() {
+		Ok(app) => app.files.iter().any(|file| file.dirty),
+		// the state can't be saved anyway
+		Err(_) => false,
 	}
-	false
 }
 
 fn handle_close_requested(app_handle: AppHandle) {
@@ -0,0 +1,106 @@
+use crate::artwork;
+use crate::cmd::AppState;
+use crate::error::Error;
+use crate::image::{default_index, get_image_data};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tauri::http::{Request, Response, ResponseBuilder};
+use tauri::{AppHandle, Manager};
+
+/// Downscaled artwork, keyed by a hash of the original image and the size
+#[derive(Default)]
+pub struct ThumbnailCache(Mutex<HashMap<(u64, u32), Vec<u8>>>);
+impl ThumbnailCache {
+	/// The cache is only a copy, so it's fine to use after a panic
+	fn lock(&self) -> MutexGuard<'_, HashMap<(u64, u32), Vec<u8>>> {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+const MAX_CACHED_THUMBNAILS: usize = 500;
+/// Thumbnail sizes are clamped to this range
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+struct ArtRequest {
+	file_index: usize,
+	/// None for the default image, usually the front cover
+	image_index: Option<usize>,
+	thumbnail_size: Option<u32>,
+}
+
+/// Parses `art://localhost/<file>/<index>?thumb=<size>`, where `<index>` can
+/// be `default`. Windows uses `https://art.localhost/` instead.
+fn parse_uri(uri: &str) -> Option<ArtRequest> {
+	let rest = uri
+		.strip_prefix("art://localhost/")
+		.or_else(|| uri.strip_prefix("https://art.localhost/"))?;
+	let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+	let mut parts = path.split('/');
+	let file_index = parts.next()?.parse().ok()?;
+	let image_index = match parts.next()? {
+		"default" => None,
+		index => Some(index.parse().ok()?),
+	};
+	let thumbnail_size = query
+		.split('&')
+		.find_map(|pair| pair.strip_prefix("thumb="))
+		.and_then(|size| size.parse::<u32>().ok())
+		.map(|size| size.clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE));
+	Some(ArtRequest {
+		file_index,
+		image_index,
+		thumbnail_size,
+	})
+}
+
+fn get_thumbnail(app: &AppHandle, data: &[u8], size: u32) -> Result<Vec<u8>, Error> {
+	let key = (artwork::hash_bytes(data), size);
+	let cache = app.state::<ThumbnailCache>();
+	if let Some(thumbnail) = cache.lock().get(&key) {
+		return Ok(thumbnail.clone());
+	}
+	let thumbnail = artwork::thumbnail(data, size)?;
+	let mut cache = cache.lock();
+	if cache.len() >= MAX_CACHED_THUMBNAILS {
+		cache.clear();
+	}
+	cache.insert(key, thumbnail.clone());
+	Ok(thumbnail)
+}
+
+/// Serves artwork of open files, so the frontend doesn't need to receive
+/// images as base64 over IPC
+pub fn handle_art_request(
+	app: &AppHandle,
+	request: &Request,
+) -> Result<Response, Box<dyn std::error::Error>> {
+	let art_request = match parse_uri(request.uri()) {
+		Some(art_request) => art_request,
+		None => return ResponseBuilder::new().status(400).body(Vec::new()),
+	};
+	// copy the image so the app isn't locked while making thumbnails
+	let image = {
+		let state = app.state::<AppState>();
+		let app = state.lock()?;
+		app.files.get(art_request.file_index).and_then(|file| {
+			let index = art_request
+				.image_index
+				.or_else(|| default_index(&file.metadata))?;
+			let (data, mime_type) = get_image_data(&file.metadata, index)?;
+			Some((data.to_vec(), mime_type))
+		})
+	};
+	let (data, mime_type) = match image {
+		Some(image) => image,
+		None => return ResponseBuilder::new().status(404).body(Vec::new()),
+	};
+	let (data, mime_type) = match art_request.thumbnail_size {
+		Some(size) => (get_thumbnail(app, &data, size)?, "image/jpeg".to_string()),
+		None => (data, mime_type),
+	};
+	ResponseBuilder::new()
+		.mimetype(&mime_type)
+		.header("Cache-Control", "no-cache")
+		.body(data)
+}
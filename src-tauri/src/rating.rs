@@ -0,0 +1,263 @@
+use crate::cmd::AppArg;
+use crate::error::Error;
+use crate::frames::Metadata;
+use crate::save;
+use crate::throw;
+use id3::TagLike;
+use mp4ameta::Fourcc;
+use serde::Serialize;
+use tauri::{command, AppHandle};
+
+/// POPM frames are keyed by an email address identifying the player that
+/// wrote them. Windows Media Player's is the most widely read.
+pub fn default_popm_email() -> String {
+	"Windows Media Player 9 Series".to_string()
+}
+
+/// Rating is in stars from 0 to 5, in steps of 0.5
+#[derive(Serialize)]
+pub struct Rating {
+	pub stars: Option<f64>,
+	pub play_count: Option<u64>,
+}
+
+// The `rtng` atom is the iTunes content advisory (explicit/clean), not a star
+// rating, so ratings go in the `rate` atom as 0-100 instead
+const MP4_RATE: Fourcc = Fourcc(*b"rate");
+
+fn round_stars(stars: f64) -> f64 {
+	(stars.clamp(0.0, 5.0) * 2.0).round() / 2.0
+}
+
+/// Uses the same ranges as Windows Media Player and Mp3tag
+fn popm_to_stars(rating: u8) -> Option<f64> {
+	match rating {
+		0 => None,
+		1..=31 => Some(1.0),
+		32..=95 => Some(2.0),
+		96..=159 => Some(3.0),
+		160..=223 => Some(4.0),
+		224..=255 => Some(5.0),
+	}
+}
+
+/// POPM ratings only have whole stars
+fn stars_to_popm(stars: f64) -> u8 {
+	match stars.round() as u8 {
+		0 => 0,
+		1 => 1,
+		2 => 64,
+		3 => 128,
+		4 => 196,
+		_ => 255,
+	}
+}
+
+/// Vorbis RATING is usually 0-100, but some taggers write 0-5
+fn vorbis_to_stars(value: &str) -> Option<f64> {
+	let value: f64 = value.trim().parse().ok()?;
+	if value <= 5.0 {
+		Some(round_stars(value))
+	} else {
+		Some(round_stars(value / 20.0))
+	}
+}
+
+fn get_popm<'a>(tag: &'a id3::Tag, email: &str) -> Option<&'a id3::frame::Popularimeter> {
+	let mut popms = tag
+		.frames()
+		.filter_map(|frame| frame.content().popularimeter());
+	match popms.clone().find(|popm| popm.user == email) {
+		Some(popm) => Some(popm),
+		None => popms.next(),
+	}
+}
+
+fn get_pcnt(tag: &id3::Tag) -> Option<u64> {
+	let data = tag.get("PCNT")?.content().unknown()?;
+	let mut count: u64 = 0;
+	for byte in data {
+		count = (count << 8) | *byte as u64;
+	}
+	Some(count)
+}
+
+pub fn get_rating(metadata: &Metadata, popm_email: &str) -> Rating {
+	match metadata {
+		Metadata::Id3(tag) => {
+			let popm = get_popm(tag, popm_email);
+			Rating {
+				stars: popm.and_then(|popm| popm_to_stars(popm.rating)),
+				play_count: match get_pcnt(tag) {
+					Some(count) => Some(count),
+					None => popm.map(|popm| popm.counter),
+				},
+			}
+		}
+		Metadata::Mp4(tag) => Rating {
+			stars: tag
+				.strings_of(&MP4_RATE)
+				.next()
+				.and_then(|s| s.trim().parse::<f64>().ok())
+				.map(|value| round_stars(value / 20.0)),
+			play_count: None,
+		},
+		Metadata::VorbisComments(tag) => Rating {
+			stars: match tag.get("RATING") {
+				Some(value) => vorbis_to_stars(value),
+				// FMPS ratings are 0.0-1.0
+				None => tag
+					.get("FMPS_RATING")
+					.and_then(|s| s.trim().parse::<f64>().ok())
+					.map(|value| round_stars(value * 5.0)),
+			},
+			play_count: tag
+				.get("FMPS_PLAYCOUNT")
+				.or_else(|| tag.get("PLAYCOUNT"))
+				.and_then(|s| s.trim().parse::<f64>().ok())
+				.map(|count| count as u64),
+		},
+	}
+}
+
+fn set_popm(tag: &mut id3::Tag, email: &str, rating: Option<u8>, counter: Option<u64>) {
+	let old = get_popm(tag, email).filter(|popm| popm.user == email);
+	let popm = id3::frame::Popularimeter {
+		user: email.to_string(),
+		rating: rating.unwrap_or_else(|| old.map(|popm| popm.rating).unwrap_or(0)),
+		counter: counter.unwrap_or_else(|| old.map(|popm| popm.counter).unwrap_or(0)),
+	};
+	let mut popm_frames: Vec<_> = tag
+		.frames()
+		.filter(|frame| match frame.content().popularimeter() {
+			Some(popm) => popm.user != email,
+			None => false,
+		})
+		.map(|frame| frame.clone())
+		.collect();
+	if popm.rating != 0 || popm.counter != 0 {
+		popm_frames.push(id3::Frame::with_content(
+			"POPM",
+			id3::Content::Popularimeter(popm),
+		));
+	}
+	tag.remove("POPM");
+	for popm_frame in popm_frames {
+		tag.add_frame(popm_frame);
+	}
+}
+
+/// Clear the rating of every POPM frame, keeping their play counts
+fn clear_popm_ratings(tag: &mut id3::Tag) {
+	let popms: Vec<_> = tag
+		.frames()
+		.filter_map(|frame| frame.content().popularimeter())
+		.filter(|popm| popm.counter != 0)
+		.map(|popm| id3::frame::Popularimeter {
+			rating: 0,
+			..popm.clone()
+		})
+		.collect();
+	tag.remove("POPM");
+	for popm in popms {
+		tag.add_frame(id3::Frame::with_content(
+			"POPM",
+			id3::Content::Popularimeter(popm),
+		));
+	}
+}
+
+pub fn set_stars(metadata: &mut Metadata, stars: Option<f64>, popm_email: &str) {
+	let stars = stars.map(round_stars).filter(|stars| *stars > 0.0);
+	match metadata {
+		Metadata::Id3(tag) => match stars {
+			Some(stars) => set_popm(tag, popm_email, Some(stars_to_popm(stars)), None),
+			// otherwise another player's rating would be shown instead
+			None => clear_popm_ratings(tag),
+		},
+		Metadata::Mp4(tag) => match stars {
+			Some(stars) => {
+				let value = ((stars * 20.0).round() as u8).to_string();
+				tag.set_data(MP4_RATE, mp4ameta::Data::Utf8(value));
+			}
+			None => tag.remove_data_of(&MP4_RATE),
+		},
+		Metadata::VorbisComments(tag) => {
+			let _ = tag.remove("RATING");
+			let _ = tag.remove("FMPS_RATING");
+			if let Some(stars) = stars {
+				let value = ((stars * 20.0).round() as u8).to_string();
+				tag.push("RATING".to_string(), value);
+				tag.push("FMPS_RATING".to_string(), (stars / 5.0).to_string());
+			}
+		}
+	}
+}
+
+pub fn set_play_count(
+	metadata: &mut Metadata,
+	count: Option<u64>,
+	popm_email: &str,
+) -> Result<(), Error> {
+	match metadata {
+		Metadata::Id3(tag) => {
+			tag.remove("PCNT");
+			if let Some(count) = count {
+				// PCNT is at least 4 bytes, growing when it would overflow
+				let bytes = count.to_be_bytes();
+				let start = bytes.iter().position(|b| *b != 0).unwrap_or(8).min(4);
+				let content = id3::frame::Unknown {
+					data: bytes[start..].to_vec(),
+					version: id3::Version::Id3v24,
+				};
+				tag.add_frame(id3::Frame::with_content(
+					"PCNT",
+					id3::Content::Unknown(content),
+				));
+			}
+			set_popm(tag, popm_email, None, Some(count.unwrap_or(0)));
+		}
+		Metadata::Mp4(_) => throw!(UnsupportedFormat: "MP4 files have no play count field"),
+		Metadata::VorbisComments(tag) => {
+			let _ = tag.remove("FMPS_PLAYCOUNT");
+			let _ = tag.remove("PLAYCOUNT");
+			if let Some(count) = count {
+				tag.push("FMPS_PLAYCOUNT".to_string(), count.to_string());
+			}
+		}
+	}
+	Ok(())
+}
+
+#[command]
+pub fn set_rating(stars: Option<f64>, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.current_file()?;
+	set_stars(&mut file.metadata, stars, &popm_email);
+	file.dirty = true;
+	Ok(())
+}
+
+#[command]
+pub fn set_rating_play_count(count: Option<u64>, app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.current_file()?;
+	set_play_count(&mut file.metadata, count, &popm_email)?;
+	file.dirty = true;
+	Ok(())
+}
+
+/// Set which POPM email ratings are read from and written to. It's
+/// remembered between sessions.
+#[command]
+pub fn set_popm_email(email: String, app: AppArg<'_>, app_handle: AppHandle) -> Result<(), Error> {
+	if email.is_empty() {
+		throw!(InvalidInput: "The rating email can't be empty");
+	}
+	let mut app = app.lock()?;
+	app.popm_email = email.clone();
+	app.save_options.popm_email = Some(email);
+	save::store_options(&app_handle, &app.save_options)
+}
@@ -0,0 +1,269 @@
+use crate::cmd::AppArg;
+use crate::error::{Error, ErrorKind};
+use crate::frames::Metadata;
+use crate::throw;
+use lofty::TagExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, FileTimes};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use tauri::{command, AppHandle};
+
+/// What to keep of a file before it's overwritten
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "dir")]
+pub enum BackupPolicy {
+	None,
+	/// Copy the file to `<name>.bak` next to it
+	Bak,
+	/// Copy the file into a folder, under its full original path so files
+	/// with the same name don't collide. Replaces older backups of it
+	Directory(PathBuf),
+}
+impl Default for BackupPolicy {
+	fn default() -> Self {
+		BackupPolicy::None
+	}
+}
+
+/// Remembered between sessions
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaveOptions {
+	pub backup_policy: BackupPolicy,
+	/// Keep the access and modification times of saved files, for tools
+	/// that detect changed tracks by mtime
+	pub preserve_times: bool,
+	/// The POPM email ratings are read from and written to, if it was changed
+	pub popm_email: Option<String>,
+}
+
+const OPTIONS_FILE: &str = "save_options.json";
+
+fn options_path(app: &AppHandle) -> Option<PathBuf> {
+	Some(app.path_resolver().app_config_dir()?.join(OPTIONS_FILE))
+}
+
+/// The options from the last session, or the defaults
+pub fn load_options(app: &AppHandle) -> SaveOptions {
+	options_path(app)
+		.and_then(|path| fs::read(path).ok())
+		.and_then(|json| serde_json::from_slice(&json).ok())
+		.unwrap_or_default()
+}
+
+pub fn store_options(app: &AppHandle, options: &SaveOptions) -> Result<(), Error> {
+	let path = match options_path(app) {
+		Some(path) => path,
+		None => throw!(Io: "No config folder found"),
+	};
+	if let Some(dir) = path.parent() {
+		if let Err(e) = fs::create_dir_all(dir) {
+			return Err(Error::io("Error creating config folder", dir, e));
+		}
+	}
+	let json = match serde_json::to_vec_pretty(options) {
+		Ok(json) => json,
+		Err(e) => throw!(InvalidInput: "Error serializing options: {}", e),
+	};
+	match fs::write(&path, json) {
+		Ok(()) => Ok(()),
+		Err(e) => Err(Error::io("Error saving options", &path, e)),
+	}
+}
+
+/// Write the tags into the file at `path`, in place
+fn write_tags(metadata: &Metadata, path: &Path) -> Result<(), Error> {
+	let result = match metadata {
+		Metadata::Id3(tag) => tag
+			.write_to_path(path, id3::Version::Id3v24)
+			.map_err(|e| e.description.to_string()),
+		Metadata::Mp4(tag) => tag
+			.write_to_path(path)
+			.map_err(|e| e.description.to_string()),
+		Metadata::VorbisComments(tag) => tag.save_to_path(path).map_err(|e| e.to_string()),
+	};
+	match result {
+		Ok(()) => Ok(()),
+		Err(e) => {
+			Err(Error::new(ErrorKind::Io, format!("Error writing tags: {}", e)).with_path(path))
+		}
+	}
+}
+
+/// Hidden, in the same folder so it can be renamed atomically, and with the
+/// same extension since some formats are detected by it
+fn temp_path(dest: &Path) -> Result<PathBuf, Error> {
+	let file_name = match dest.file_name() {
+		Some(file_name) => file_name.to_string_lossy(),
+		None => throw!(InvalidInput: "Invalid file path"),
+	};
+	Ok(dest.with_file_name(format!(".{}-{}", process::id(), file_name)))
+}
+
+/// Give the temp file the original's permissions, ownership and extended
+/// attributes. Ownership and attributes are best effort, since changing
+/// them is often not allowed.
+fn copy_attributes(original: &Path, temp: &Path) -> Result<(), Error> {
+	let metadata = match fs::metadata(original) {
+		Ok(metadata) => metadata,
+		Err(e) => return Err(Error::io("Error reading file info", original, e)),
+	};
+	if let Err(e) = fs::set_permissions(temp, metadata.permissions()) {
+		return Err(Error::io("Error setting permissions", temp, e));
+	}
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+		let _ = std::os::unix::fs::chown(temp, Some(metadata.uid()), Some(metadata.gid()));
+		if let Ok(names) = xattr::list(original) {
+			for name in names {
+				if let Ok(Some(value)) = xattr::get(original, &name) {
+					let _ = xattr::set(temp, &name, &value);
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// `path` inside `dir`, e.g. `/music/a/song.mp3` becomes
+/// `<dir>/music/a/song.mp3` and `C:\music\song.mp3` becomes `<dir>\C\music\song.mp3`
+fn mirrored_path(dir: &Path, path: &Path) -> PathBuf {
+	let mut mirrored = dir.to_path_buf();
+	for component in path.components() {
+		match component {
+			Component::Prefix(prefix) => {
+				let prefix = prefix.as_os_str().to_string_lossy();
+				mirrored.push(prefix.replace(|c: char| !c.is_alphanumeric(), ""));
+			}
+			Component::Normal(name) => mirrored.push(name),
+			Component::RootDir | Component::CurDir | Component::ParentDir => {}
+		}
+	}
+	mirrored
+}
+
+fn backup(path: &Path, policy: &BackupPolicy) -> Result<(), Error> {
+	let backup_path = match policy {
+		BackupPolicy::None => return Ok(()),
+		BackupPolicy::Bak => {
+			let mut name = path.as_os_str().to_os_string();
+			name.push(".bak");
+			PathBuf::from(name)
+		}
+		BackupPolicy::Directory(dir) => {
+			let backup_path = mirrored_path(dir, path);
+			let backup_dir = match backup_path.parent() {
+				Some(backup_dir) if path.file_name().is_some() => backup_dir,
+				_ => throw!(InvalidInput: "Invalid file path"),
+			};
+			if let Err(e) = fs::create_dir_all(backup_dir) {
+				return Err(Error::io("Error creating backup folder", backup_dir, e));
+			}
+			backup_path
+		}
+	};
+	match fs::copy(path, &backup_path) {
+		Ok(_) => Ok(()),
+		Err(e) => Err(Error::io("Error creating backup", &backup_path, e)),
+	}
+}
+
+/// The access and modification times of `path`. Must be read before the
+/// file is read, since that updates the access time.
+pub fn get_times(path: &Path) -> Result<FileTimes, Error> {
+	let metadata = match fs::metadata(path) {
+		Ok(metadata) => metadata,
+		Err(e) => return Err(Error::io("Error reading file info", path, e)),
+	};
+	let mut times = FileTimes::new();
+	if let Ok(accessed) = metadata.accessed() {
+		times = times.set_accessed(accessed);
+	}
+	if let Ok(modified) = metadata.modified() {
+		times = times.set_modified(modified);
+	}
+	Ok(times)
+}
+
+/// Save the tags of `source` to `dest` without ever leaving a half-written
+/// file. The audio is copied to a temp file next to `dest`, the tags are
+/// written and synced to disk, and the temp file is renamed over `dest`.
+/// `source` and `dest` are the same path, except for Save As. `times` are
+/// the times recorded when `source` was opened, since reading it since then
+/// has changed its access time.
+pub fn save_atomic(
+	metadata: &Metadata,
+	source: &Path,
+	dest: &Path,
+	times: Option<FileTimes>,
+	options: &SaveOptions,
+) -> Result<(), Error> {
+	let temp = temp_path(dest)?;
+	let result = (|| -> Result<(), Error> {
+		let times = match (options.preserve_times, times) {
+			(false, _) => None,
+			(true, Some(times)) => Some(times),
+			(true, None) => Some(get_times(source)?),
+		};
+		if let Err(e) = fs::copy(source, &temp) {
+			return Err(Error::io("Error copying file", &temp, e));
+		}
+		write_tags(metadata, &temp)?;
+		copy_attributes(source, &temp)?;
+		let sync = fs::OpenOptions::new()
+			.write(true)
+			.open(&temp)
+			.and_then(|file| {
+				if let Some(times) = times {
+					file.set_times(times)?;
+				}
+				file.sync_all()
+			});
+		if let Err(e) = sync {
+			return Err(Error::io("Error writing file", &temp, e));
+		}
+		if dest.exists() {
+			backup(dest, &options.backup_policy)?;
+		}
+		match fs::rename(&temp, dest) {
+			Ok(()) => Ok(()),
+			Err(e) => Err(Error::io("Error replacing file", dest, e)),
+		}
+	})();
+	if result.is_err() {
+		let _ = fs::remove_file(&temp);
+		return result;
+	}
+	// make the rename itself durable
+	#[cfg(unix)]
+	{
+		if let Some(dir) = dest.parent() {
+			let _ = fs::File::open(dir).and_then(|dir| dir.sync_all());
+		}
+	}
+	Ok(())
+}
+
+#[command]
+pub fn set_backup_policy(
+	policy: BackupPolicy,
+	app: AppArg<'_>,
+	app_handle: AppHandle,
+) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	app.save_options.backup_policy = policy;
+	store_options(&app_handle, &app.save_options)
+}
+
+#[command]
+pub fn set_preserve_times(
+	preserve: bool,
+	app: AppArg<'_>,
+	app_handle: AppHandle,
+) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	app.save_options.preserve_times = preserve;
+	store_options(&app_handle, &app.save_options)
+}
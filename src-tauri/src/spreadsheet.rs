@@ -0,0 +1,427 @@
+use crate::cmd::{AppArg, File};
+use crate::comments::Comment;
+use crate::error::{Error, ErrorKind};
+use crate::fields::{self, Field, FieldValue, FIELDS};
+use crate::frames::{Frame, Metadata};
+use crate::lyrics::Lyrics;
+use crate::throw;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tauri::command;
+
+/// Separates multiple values of text fields
+const VALUE_SEPARATOR: &str = "; ";
+/// Separates multiple comments or lyrics, which often contain "; "
+const TEXT_SEPARATOR: &str = "\n\n";
+
+const PATH_COLUMN: &str = "Path";
+/// Raw frames are exported for reference, but not imported
+const FRAME_PREFIX: &str = "Frame:";
+
+/// Fields that can be edited in a spreadsheet
+fn table_fields() -> impl Iterator<Item = Field> {
+	FIELDS
+		.iter()
+		.copied()
+		.filter(|field| *field != Field::Artwork)
+}
+
+pub fn field_to_string(value: FieldValue) -> String {
+	match value {
+		FieldValue::Text(values) => values.join(VALUE_SEPARATOR),
+		FieldValue::Comments(comments) => comments
+			.into_iter()
+			.filter(|comment| !comment.protected)
+			.map(|comment| comment.text)
+			.collect::<Vec<_>>()
+			.join(TEXT_SEPARATOR),
+		FieldValue::Lyrics(lyrics) => lyrics
+			.into_iter()
+			.map(|lyrics| lyrics.text)
+			.collect::<Vec<_>>()
+			.join(TEXT_SEPARATOR),
+		FieldValue::Stars(stars) => stars.map(|s| s.to_string()).unwrap_or_default(),
+		FieldValue::Pictures(pictures) => pictures.len().to_string(),
+	}
+}
+
+/// Fields that often have several values. Other fields are kept whole, since
+/// titles and the like can contain the separator.
+fn is_multi_value(field: Field) -> bool {
+	matches!(
+		field,
+		Field::Artist | Field::AlbumArtist | Field::Composer | Field::Genre
+	)
+}
+
+/// Parse a field from text. Comments and lyrics keep the language,
+/// description and key of the current ones at the same position.
+fn field_from_string(field: Field, s: &str, current: FieldValue) -> Result<FieldValue, Error> {
+	let split = |separator: &str| {
+		s.split(separator)
+			.map(|s| s.to_string())
+			.filter(|s| !s.is_empty())
+			.collect::<Vec<_>>()
+	};
+	let value = match (field, current) {
+		(Field::Comment, FieldValue::Comments(current)) => {
+			let current: Vec<_> = current.into_iter().filter(|c| !c.protected).collect();
+			FieldValue::Comments(
+				split(TEXT_SEPARATOR)
+					.into_iter()
+					.enumerate()
+					.map(|(i, text)| match current.get(i) {
+						Some(comment) => Comment {
+							text,
+							..comment.clone()
+						},
+						None => Comment {
+							text,
+							lang: None,
+							description: None,
+							protected: false,
+							key: None,
+						},
+					})
+					.collect(),
+			)
+		}
+		(Field::Lyrics, FieldValue::Lyrics(current)) => FieldValue::Lyrics(
+			split(TEXT_SEPARATOR)
+				.into_iter()
+				.enumerate()
+				.map(|(i, text)| match current.get(i) {
+					Some(lyrics) => Lyrics {
+						text,
+						..lyrics.clone()
+					},
+					None => Lyrics {
+						text,
+						lang: None,
+						description: None,
+						key: None,
+					},
+				})
+				.collect(),
+		),
+		(Field::Rating, _) => FieldValue::Stars(match s.trim() {
+			"" => None,
+			stars => match stars.parse() {
+				Ok(stars) => Some(stars),
+				Err(_) => throw!(Parse: "Invalid rating: {}", stars),
+			},
+		}),
+		(Field::Artwork, _) => throw!(UnsupportedFormat: "Artwork can't be imported"),
+		(field, _) if is_multi_value(field) => FieldValue::Text(split(VALUE_SEPARATOR)),
+		_ if s.is_empty() => FieldValue::Text(Vec::new()),
+		_ => FieldValue::Text(vec![s.to_string()]),
+	};
+	Ok(value)
+}
+
+/// Column names and values
+pub type Row = Vec<(String, String)>;
+
+/// The fields and raw frames of a tag as text
+pub fn metadata_to_row(metadata: &Metadata, popm_email: &str) -> Row {
+	let mut row = Vec::new();
+	for field in table_fields() {
+		let value = fields::get_field(metadata, field, popm_email);
+		row.push((format!("{:?}", field), field_to_string(value)));
+	}
+	for frame in metadata.get_frames() {
+		let Frame::Text { id, value } = frame;
+		let column = format!("{}{}", FRAME_PREFIX, id);
+		match row.iter_mut().find(|(c, _)| *c == column) {
+			Some((_, existing)) => {
+				existing.push_str(TEXT_SEPARATOR);
+				existing.push_str(&value);
+			}
+			None => row.push((column, value)),
+		}
+	}
+	row
+}
+
+fn file_to_row(file: &File, popm_email: &str) -> Row {
+	let mut row = vec![(
+		PATH_COLUMN.to_string(),
+		file.path.to_string_lossy().to_string(),
+	)];
+	row.extend(metadata_to_row(&file.metadata, popm_email));
+	row
+}
+
+fn csv_escape(value: &str) -> String {
+	if value.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
+
+fn to_csv(rows: &[Row]) -> String {
+	// files have different frames, so collect all columns
+	let mut columns: Vec<&str> = Vec::new();
+	for row in rows {
+		for (column, _) in row {
+			if !columns.contains(&column.as_str()) {
+				columns.push(column);
+			}
+		}
+	}
+	let mut csv = String::new();
+	let header: Vec<_> = columns.iter().map(|c| csv_escape(c)).collect();
+	csv.push_str(&header.join(","));
+	csv.push_str("\r\n");
+	for row in rows {
+		let cells: Vec<_> = columns
+			.iter()
+			.map(|column| match row.iter().find(|(c, _)| c == column) {
+				Some((_, value)) => csv_escape(value),
+				None => "".to_string(),
+			})
+			.collect();
+		csv.push_str(&cells.join(","));
+		csv.push_str("\r\n");
+	}
+	csv
+}
+
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+	let mut records = Vec::new();
+	let mut record = Vec::new();
+	let mut cell = String::new();
+	let mut in_quotes = false;
+	let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+	while let Some(c) = chars.next() {
+		if in_quotes {
+			match c {
+				'"' if chars.peek() == Some(&'"') => {
+					chars.next();
+					cell.push('"');
+				}
+				'"' => in_quotes = false,
+				c => cell.push(c),
+			}
+			continue;
+		}
+		match c {
+			'"' => in_quotes = true,
+			',' => record.push(std::mem::take(&mut cell)),
+			'\r' if chars.peek() == Some(&'\n') => {}
+			'\n' | '\r' => {
+				record.push(std::mem::take(&mut cell));
+				records.push(std::mem::take(&mut record));
+			}
+			c => cell.push(c),
+		}
+	}
+	if !cell.is_empty() || !record.is_empty() {
+		record.push(cell);
+		records.push(record);
+	}
+	records
+}
+
+fn rows_from_csv(text: &str) -> Vec<Row> {
+	let mut records = parse_csv(text).into_iter();
+	let header = match records.next() {
+		Some(header) => header,
+		None => return Vec::new(),
+	};
+	records
+		.filter(|record| record.iter().any(|cell| !cell.is_empty()))
+		.map(|record| header.iter().cloned().zip(record).collect())
+		.collect()
+}
+
+fn rows_from_json(text: &str) -> Result<Vec<Row>, Error> {
+	let rows: Vec<Map<String, Value>> = match serde_json::from_str(text) {
+		Ok(rows) => rows,
+		Err(e) => throw!(Parse: "Invalid JSON: {}", e),
+	};
+	Ok(rows
+		.into_iter()
+		.map(|row| {
+			row.into_iter()
+				.map(|(column, value)| match value {
+					Value::String(s) => (column, s),
+					Value::Null => (column, "".to_string()),
+					value => (column, value.to_string()),
+				})
+				.collect()
+		})
+		.collect())
+}
+
+fn is_json(path: &Path) -> bool {
+	let ext = path.extension().unwrap_or_default().to_string_lossy();
+	ext.eq_ignore_ascii_case("json")
+}
+
+/// Write the fields and raw frames of every open file to a CSV or JSON file,
+/// depending on the extension
+#[command]
+pub fn export_tag_table(path: PathBuf, app: AppArg<'_>) -> Result<(), Error> {
+	let app = app.lock()?;
+	let rows: Vec<_> = app
+		.files
+		.iter()
+		.map(|file| file_to_row(file, &app.popm_email))
+		.collect();
+	let text = if is_json(&path) {
+		let rows: Vec<Map<String, Value>> = rows
+			.into_iter()
+			.map(|row| {
+				row.into_iter()
+					.map(|(column, value)| (column, Value::String(value)))
+					.collect()
+			})
+			.collect();
+		match serde_json::to_string_pretty(&rows) {
+			Ok(json) => json,
+			Err(e) => throw!(InvalidInput: "Error creating JSON: {}", e),
+		}
+	} else {
+		to_csv(&rows)
+	};
+	match fs::write(&path, text) {
+		Ok(()) => Ok(()),
+		Err(e) => return Err(Error::io("Error writing file", &path, e)),
+	}
+}
+
+#[derive(Clone, Serialize)]
+pub struct FieldChange {
+	field: Field,
+	old: String,
+	new: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FileChanges {
+	path: PathBuf,
+	changes: Vec<FieldChange>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TagImport {
+	files: Vec<FileChanges>,
+	/// Paths in the table that aren't open
+	unmatched: Vec<String>,
+}
+
+fn read_import(path: &Path, files: &[File], popm_email: &str) -> Result<TagImport, Error> {
+	let text = match fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(e) => return Err(Error::io("Error reading file", &path, e)),
+	};
+	let rows = if is_json(path) {
+		rows_from_json(&text)?
+	} else {
+		rows_from_csv(&text)
+	};
+	let mut import = TagImport {
+		files: Vec::new(),
+		unmatched: Vec::new(),
+	};
+	for row in rows {
+		let row_path = match row.iter().find(|(column, _)| column == PATH_COLUMN) {
+			Some((_, path)) => path,
+			None => throw!(Parse: "The table has no {} column", PATH_COLUMN),
+		};
+		let file = match files.iter().find(|f| f.path == Path::new(row_path)) {
+			Some(file) => file,
+			None => {
+				import.unmatched.push(row_path.clone());
+				continue;
+			}
+		};
+		let current = file_to_row(file, popm_email);
+		let mut changes = Vec::new();
+		for field in table_fields() {
+			let column = format!("{:?}", field);
+			let new = match row.iter().find(|(c, _)| *c == column) {
+				Some((_, new)) => new,
+				None => continue,
+			};
+			let old = match current.iter().find(|(c, _)| *c == column) {
+				Some((_, old)) => old,
+				None => continue,
+			};
+			if old != new {
+				changes.push(FieldChange {
+					field,
+					old: old.clone(),
+					new: new.clone(),
+				});
+			}
+		}
+		if !changes.is_empty() {
+			import.files.push(FileChanges {
+				path: file.path.clone(),
+				changes,
+			});
+		}
+	}
+	Ok(import)
+}
+
+/// Read a CSV or JSON table and list how it differs from the open files. The
+/// changes are kept until they are applied.
+#[command]
+pub fn preview_tag_import(path: PathBuf, app: AppArg<'_>) -> Result<TagImport, Error> {
+	let mut app = app.lock()?;
+	let import = read_import(&path, &app.files, &app.popm_email)?;
+	app.tag_import = Some(import.clone());
+	Ok(import)
+}
+
+/// Apply the changes from the last preview to the open files. The files are
+/// marked as dirty, not saved. Nothing is changed if any change fails, or if
+/// a file was changed or closed since the preview.
+#[command]
+pub fn apply_tag_import(app: AppArg<'_>) -> Result<(), Error> {
+	let mut app = app.lock()?;
+	let import = match &app.tag_import {
+		Some(import) => import,
+		None => throw!(InvalidInput: "There are no previewed changes to apply"),
+	};
+	let mut updates = Vec::new();
+	for file_changes in &import.files {
+		let index = match app.files.iter().position(|f| f.path == file_changes.path) {
+			Some(index) => index,
+			None => {
+				let e = Error::new(ErrorKind::Conflict, "The file was closed since the preview");
+				return Err(e.with_path(&file_changes.path));
+			}
+		};
+		let file = &app.files[index];
+		let mut metadata = file.metadata.clone();
+		for change in &file_changes.changes {
+			let current = fields::get_field(&file.metadata, change.field, &app.popm_email);
+			if field_to_string(current.clone()) != change.old {
+				let message = format!("{:?} was changed since the preview", change.field);
+				return Err(Error::new(ErrorKind::Conflict, message).with_path(&file.path));
+			}
+			let result = field_from_string(change.field, &change.new, current).and_then(|value| {
+				fields::set_field(&mut metadata, change.field, value, &app.popm_email)
+			});
+			if let Err(e) = result {
+				let e = e.context(&format!("Error setting {:?}", change.field));
+				return Err(e.with_path(&file.path));
+			}
+		}
+		updates.push((index, metadata));
+	}
+	for (index, metadata) in updates {
+		let file = &mut app.files[index];
+		file.metadata = metadata;
+		file.dirty = true;
+	}
+	app.tag_import = None;
+	Ok(())
+}
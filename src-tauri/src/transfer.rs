@@ -0,0 +1,434 @@
+use crate::artwork;
+use crate::cmd::AppArg;
+use crate::error::Error;
+use crate::fields::{self, Field, FieldValue, FIELDS};
+use crate::frames::Metadata;
+use crate::rating;
+use crate::throw;
+use id3::TagLike;
+use mp4ameta::{Data, DataIdent, Fourcc, FreeformIdent};
+use serde::Serialize;
+use tauri::command;
+
+/// Vorbis keys that are stored in standard ID3 frames and MP4 atoms. Keys
+/// with no MP4 atom are stored as iTunes freeform atoms.
+const STANDARD_EXTRAS: [(&str, &str, Option<Fourcc>); 18] = [
+	("LYRICIST", "TEXT", None),
+	("CONDUCTOR", "TPE3", None),
+	("REMIXER", "TPE4", None),
+	("ISRC", "TSRC", None),
+	("LABEL", "TPUB", None),
+	("COPYRIGHT", "TCOP", Some(Fourcc(*b"cprt"))),
+	("ENCODEDBY", "TENC", None),
+	("ENCODER", "TSSE", Some(Fourcc(*b"\xa9too"))),
+	("TITLESORT", "TSOT", Some(Fourcc(*b"sonm"))),
+	("ARTISTSORT", "TSOP", Some(Fourcc(*b"soar"))),
+	("ALBUMSORT", "TSOA", Some(Fourcc(*b"soal"))),
+	("ALBUMARTISTSORT", "TSO2", Some(Fourcc(*b"soaa"))),
+	("COMPOSERSORT", "TSOC", Some(Fourcc(*b"soco"))),
+	("MOOD", "TMOO", None),
+	("MEDIA", "TMED", None),
+	("SUBTITLE", "TIT3", None),
+	("ORIGINALDATE", "TDOR", None),
+	("LANGUAGE", "TLAN", None),
+];
+
+/// Vorbis keys and the names Picard uses for ID3 TXXX frames and MP4
+/// freeform atoms
+const MUSICBRAINZ_NAMES: [(&str, &str); 12] = [
+	("MUSICBRAINZ_TRACKID", "MusicBrainz Track Id"),
+	("MUSICBRAINZ_RELEASETRACKID", "MusicBrainz Release Track Id"),
+	("MUSICBRAINZ_ALBUMID", "MusicBrainz Album Id"),
+	("MUSICBRAINZ_ARTISTID", "MusicBrainz Artist Id"),
+	("MUSICBRAINZ_ALBUMARTISTID", "MusicBrainz Album Artist Id"),
+	("MUSICBRAINZ_RELEASEGROUPID", "MusicBrainz Release Group Id"),
+	("MUSICBRAINZ_WORKID", "MusicBrainz Work Id"),
+	("MUSICBRAINZ_DISCID", "MusicBrainz Disc Id"),
+	("RELEASESTATUS", "MusicBrainz Album Status"),
+	("RELEASETYPE", "MusicBrainz Album Type"),
+	("RELEASECOUNTRY", "MusicBrainz Album Release Country"),
+	("ACOUSTID_ID", "Acoustid Id"),
+];
+
+/// ID3 stores the MusicBrainz recording ID in a UFID frame instead of TXXX
+const MUSICBRAINZ_UFID_OWNER: &str = "http://musicbrainz.org";
+
+const ITUNES_MEAN: &str = "com.apple.iTunes";
+
+/// ID3 frames that are copied as fields, and TXXX which is copied as extras.
+/// Text frames in STANDARD_EXTRAS and MusicBrainz UFID frames are also copied.
+const ID3_COPIED_FRAMES: [&str; 21] = [
+	"TIT2", "TPE1", "TALB", "TPE2", "TCOM", "GRP1", "GP1", "TCON", "TDRC", "TYER", "TCMP", "TCP",
+	"TBPM", "TRCK", "TPOS", "COMM", "USLT", "POPM", "PCNT", "APIC", "TXXX",
+];
+
+/// MP4 atoms that are copied as fields. Atoms in STANDARD_EXTRAS and UTF-8
+/// freeform atoms are copied as extras.
+const MP4_FIELD_ATOMS: [Fourcc; 17] = [
+	Fourcc(*b"\xa9nam"),
+	Fourcc(*b"\xa9ART"),
+	Fourcc(*b"\xa9alb"),
+	Fourcc(*b"aART"),
+	Fourcc(*b"\xa9wrt"),
+	Fourcc(*b"\xa9grp"),
+	Fourcc(*b"\xa9gen"),
+	Fourcc(*b"gnre"),
+	Fourcc(*b"\xa9day"),
+	Fourcc(*b"trkn"),
+	Fourcc(*b"disk"),
+	Fourcc(*b"cpil"),
+	Fourcc(*b"tmpo"),
+	Fourcc(*b"\xa9cmt"),
+	Fourcc(*b"\xa9lyr"),
+	Fourcc(*b"covr"),
+	Fourcc(*b"rate"),
+];
+
+/// Vorbis keys that are handled as fields, or aren't tags
+const VORBIS_FIELD_KEYS: [&str; 12] = [
+	"TOTALTRACKS",
+	"TOTALDISCS",
+	"COMMENT",
+	"DESCRIPTION",
+	"LYRICS",
+	"UNSYNCEDLYRICS",
+	"RATING",
+	"FMPS_RATING",
+	"FMPS_PLAYCOUNT",
+	"PLAYCOUNT",
+	"METADATA_BLOCK_PICTURE",
+	"COVERART",
+];
+
+/// ID3 frames and MP4 atoms name extras differently from Vorbis keys
+fn name_to_key(name: &str) -> String {
+	match MUSICBRAINZ_NAMES
+		.iter()
+		.find(|(_, n)| n.eq_ignore_ascii_case(name))
+	{
+		Some((key, _)) => key.to_string(),
+		None => name.to_uppercase(),
+	}
+}
+
+fn key_to_name(key: &str) -> String {
+	match MUSICBRAINZ_NAMES.iter().find(|(k, _)| *k == key) {
+		Some((_, name)) => name.to_string(),
+		None => key.to_string(),
+	}
+}
+
+fn push_extra(extras: &mut Vec<(String, Vec<String>)>, key: String, value: String) {
+	match extras.iter_mut().find(|(k, _)| *k == key) {
+		Some((_, values)) => values.push(value),
+		None => extras.push((key, vec![value])),
+	}
+}
+
+/// Tags that aren't common fields, like MusicBrainz IDs and custom keys,
+/// named by their Vorbis key
+fn get_extras(metadata: &Metadata) -> Vec<(String, Vec<String>)> {
+	let mut extras = Vec::new();
+	match metadata {
+		Metadata::Id3(tag) => {
+			for (key, id, _) in STANDARD_EXTRAS {
+				if let Some(text) = tag.get(id).and_then(|frame| frame.content().text()) {
+					for value in text.split('\u{0}') {
+						push_extra(&mut extras, key.to_string(), value.to_string());
+					}
+				}
+			}
+			for frame in tag.frames() {
+				match frame.content() {
+					id3::Content::ExtendedText(text) => {
+						let key = name_to_key(&text.description);
+						for value in text.value.split('\u{0}') {
+							push_extra(&mut extras, key.clone(), value.to_string());
+						}
+					}
+					id3::Content::UniqueFileIdentifier(ufid)
+						if ufid.owner_identifier == MUSICBRAINZ_UFID_OWNER =>
+					{
+						let value = String::from_utf8_lossy(&ufid.identifier).to_string();
+						push_extra(&mut extras, "MUSICBRAINZ_TRACKID".to_string(), value);
+					}
+					_ => {}
+				}
+			}
+		}
+		Metadata::Mp4(tag) => {
+			for (key, _, fourcc) in STANDARD_EXTRAS {
+				if let Some(fourcc) = fourcc {
+					for value in tag.strings_of(&fourcc) {
+						push_extra(&mut extras, key.to_string(), value.to_string());
+					}
+				}
+			}
+			for (ident, data) in tag.data() {
+				if let (DataIdent::Freeform { name, .. }, Data::Utf8(value)) = (ident, data) {
+					push_extra(&mut extras, name_to_key(name), value.clone());
+				}
+			}
+		}
+		Metadata::VorbisComments(tag) => {
+			for (key, value) in tag.items() {
+				let key = key.to_uppercase();
+				let is_field = FIELDS
+					.iter()
+					.any(|field| fields::vorbis_key(*field) == Some(key.as_str()));
+				if !is_field && !VORBIS_FIELD_KEYS.contains(&key.as_str()) {
+					push_extra(&mut extras, key, value.to_string());
+				}
+			}
+		}
+	}
+	extras
+}
+
+/// Frames and atoms that aren't copied as fields or extras, by ID. Every
+/// Vorbis key is either a field or an extra.
+fn get_uncopied(metadata: &Metadata) -> Vec<String> {
+	let mut uncopied: Vec<String> = Vec::new();
+	let mut push = |id: String| {
+		if !uncopied.contains(&id) {
+			uncopied.push(id);
+		}
+	};
+	match metadata {
+		Metadata::Id3(tag) => {
+			for frame in tag.frames() {
+				let id = frame.id();
+				let is_copied = ID3_COPIED_FRAMES.contains(&id)
+					|| STANDARD_EXTRAS
+						.iter()
+						.any(|(_, extra_id, _)| *extra_id == id)
+					|| matches!(frame.content(), id3::Content::UniqueFileIdentifier(ufid)
+						if ufid.owner_identifier == MUSICBRAINZ_UFID_OWNER);
+				if !is_copied {
+					push(id.to_string());
+				}
+			}
+		}
+		Metadata::Mp4(tag) => {
+			for (ident, data) in tag.data() {
+				let is_copied = match ident {
+					DataIdent::Fourcc(fourcc) => {
+						MP4_FIELD_ATOMS.contains(fourcc)
+							|| STANDARD_EXTRAS
+								.iter()
+								.any(|(_, _, extra)| extra.as_ref() == Some(fourcc))
+					}
+					DataIdent::Freeform { .. } => matches!(data, Data::Utf8(_)),
+				};
+				if !is_copied {
+					push(ident.to_string());
+				}
+			}
+		}
+		Metadata::VorbisComments(_) => {}
+	}
+	uncopied
+}
+
+/// Set an extra, and describe what the target format loses from it, if
+/// anything
+fn set_extra(metadata: &mut Metadata, key: &str, mut values: Vec<String>) -> Option<String> {
+	let standard = STANDARD_EXTRAS.iter().find(|(k, _, _)| *k == key);
+	let mut lost = None;
+	match metadata {
+		Metadata::Id3(tag) => {
+			if let Some((_, id, _)) = standard {
+				tag.set_text(*id, values.join("\u{0}"));
+			} else if key == "MUSICBRAINZ_TRACKID" {
+				if values.len() > 1 {
+					lost = Some("only the first value was kept".to_string());
+				}
+				let ufid = id3::frame::UniqueFileIdentifier {
+					owner_identifier: MUSICBRAINZ_UFID_OWNER.to_string(),
+					identifier: values.swap_remove(0).into_bytes(),
+				};
+				tag.add_frame(id3::Frame::with_content(
+					"UFID",
+					id3::Content::UniqueFileIdentifier(ufid),
+				));
+			} else {
+				// adding a TXXX frame replaces the one with the same description
+				tag.add_frame(id3::frame::ExtendedText {
+					description: key_to_name(key),
+					value: values.join("\u{0}"),
+				});
+			}
+		}
+		Metadata::Mp4(tag) => match standard {
+			Some((_, _, Some(fourcc))) => {
+				if values.len() > 1 {
+					lost = Some("multiple values were joined with \"; \"".to_string());
+				}
+				tag.set_data(*fourcc, Data::Utf8(values.join("; ")));
+			}
+			_ => {
+				let name = key_to_name(key);
+				tag.remove_data_of(&FreeformIdent::new(ITUNES_MEAN, &name));
+				for value in values {
+					tag.add_data(FreeformIdent::new(ITUNES_MEAN, &name), Data::Utf8(value));
+				}
+			}
+		},
+		Metadata::VorbisComments(tag) => {
+			let _ = tag.remove(key);
+			for value in values {
+				tag.push(key.to_string(), value);
+			}
+		}
+	}
+	lost
+}
+
+/// Describe what the target format loses from a field, if anything
+fn loss(field: Field, value: &FieldValue, target: &Metadata) -> Option<String> {
+	let is_mp4 = matches!(target, Metadata::Mp4(_));
+	let is_id3 = matches!(target, Metadata::Id3(_));
+	match value {
+		FieldValue::Text(values) if values.len() > 1 => match field {
+			Field::TrackNumber
+			| Field::TrackTotal
+			| Field::DiscNumber
+			| Field::DiscTotal
+			| Field::Compilation
+			| Field::Bpm
+				if !matches!(target, Metadata::VorbisComments(_)) =>
+			{
+				Some("only the first value was kept".to_string())
+			}
+			Field::Title | Field::Album | Field::Year if is_mp4 => {
+				Some("multiple values were joined with \"; \"".to_string())
+			}
+			_ => None,
+		},
+		FieldValue::Comments(comments) if !is_id3 => {
+			let has_details = comments.iter().any(|comment| {
+				let description = comment.description.as_deref().unwrap_or("");
+				let lang = comment.lang.as_deref().unwrap_or("eng");
+				!description.is_empty() || lang != "eng"
+			});
+			has_details.then(|| "languages and descriptions were dropped".to_string())
+		}
+		FieldValue::Lyrics(lyrics) if !is_id3 => {
+			let has_description = lyrics
+				.iter()
+				.any(|l| !l.description.as_deref().unwrap_or("").is_empty());
+			has_description.then(|| "descriptions were dropped".to_string())
+		}
+		FieldValue::Stars(Some(stars)) if is_id3 && stars.fract() != 0.0 => {
+			Some("half stars were rounded".to_string())
+		}
+		FieldValue::Pictures(pictures) => {
+			let mut notes = Vec::new();
+			let converted = pictures
+				.iter()
+				.filter(|pic| !artwork::supports(target, &pic.mime_type))
+				.count();
+			if converted > 0 {
+				notes.push(format!("{} converted to PNG", converted));
+			}
+			if is_mp4 {
+				let has_details = pictures.iter().enumerate().any(|(i, pic)| {
+					let default_type = if i == 0 { 3 } else { 0 };
+					pic.picture_type != default_type || !pic.description.is_empty()
+				});
+				if has_details {
+					notes.push("picture types and descriptions were dropped".to_string());
+				}
+			}
+			if notes.is_empty() {
+				None
+			} else {
+				Some(notes.join(", "))
+			}
+		}
+		_ => None,
+	}
+}
+
+#[derive(Default, Serialize)]
+pub struct TransferReport {
+	/// Fields and keys that were copied
+	copied: Vec<String>,
+	/// Fields that were copied with some loss, and what was lost
+	lossy: Vec<(String, String)>,
+	/// Fields that couldn't be copied, and why
+	failed: Vec<(String, String)>,
+	/// Frames and atoms of the source that have no equivalent in the target,
+	/// like links, private frames, synced lyrics and chapters
+	dropped: Vec<String>,
+}
+
+/// Copy all tags of one file into another, converting between formats. The
+/// target's common fields are replaced by the ones set in the source, and
+/// other keys are added. Fields that are empty in the source are only cleared
+/// in the target if `clear_empty` is set.
+pub fn transfer(
+	source: &Metadata,
+	target: &mut Metadata,
+	popm_email: &str,
+	clear_empty: bool,
+) -> TransferReport {
+	let mut report = TransferReport::default();
+	for field in FIELDS {
+		let name = format!("{:?}", field);
+		let value = fields::get_field(source, field, popm_email);
+		let is_empty = value.is_empty();
+		if is_empty && !clear_empty {
+			continue;
+		}
+		let lost = loss(field, &value, target);
+		match fields::set_field(target, field, value, popm_email) {
+			Ok(()) if is_empty => {}
+			Ok(()) => {
+				if let Some(lost) = lost {
+					report.lossy.push((name.clone(), lost));
+				}
+				report.copied.push(name);
+			}
+			Err(e) => report.failed.push((name, e.to_string())),
+		}
+	}
+	let play_count = rating::get_rating(source, popm_email).play_count;
+	if play_count.is_some() {
+		match rating::set_play_count(target, play_count, popm_email) {
+			Ok(()) => report.copied.push("PlayCount".to_string()),
+			Err(e) => report.failed.push(("PlayCount".to_string(), e.to_string())),
+		}
+	}
+	for (key, values) in get_extras(source) {
+		if let Some(lost) = set_extra(target, &key, values) {
+			report.lossy.push((key.clone(), lost));
+		}
+		report.copied.push(key);
+	}
+	report.dropped = get_uncopied(source);
+	report
+}
+
+/// Copy all tags from the open file at `from` to the open file at `to`
+#[command]
+pub fn transfer_tags(
+	from: usize,
+	to: usize,
+	clear_empty: bool,
+	app: AppArg<'_>,
+) -> Result<TransferReport, Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let source = match app.files.get(from) {
+		Some(file) => file.metadata.clone(),
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	let target = match app.files.get_mut(to) {
+		Some(file) => file,
+		None => throw!(IndexOutOfRange: "Index out of range"),
+	};
+	let report = transfer(&source, &mut target.metadata, &popm_email, clear_empty);
+	target.dirty = true;
+	Ok(report)
+}
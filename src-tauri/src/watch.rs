@@ -0,0 +1,212 @@
+use crate::cmd::{AppArg, AppState, File};
+use crate::error::Error;
+use crate::files;
+use crate::frames::Metadata;
+use crate::spreadsheet::{self, Row};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tauri::{command, AppHandle, Manager};
+
+/// What a file looked like on disk when it was last read or written. The
+/// hash is of the tag rather than the whole file, so opening and saving
+/// don't read whole files.
+#[derive(Clone, PartialEq)]
+pub struct Fingerprint {
+	size: u64,
+	modified: Option<SystemTime>,
+	tag_hash: u64,
+}
+
+/// Record the file info of `path`, and `tag` as read from it
+pub fn fingerprint(path: &Path, tag: &Metadata) -> Result<Fingerprint, Error> {
+	let metadata = match fs::metadata(path) {
+		Ok(metadata) => metadata,
+		Err(e) => return Err(Error::io("Error reading file info", path, e)),
+	};
+	Ok(Fingerprint {
+		size: metadata.len(),
+		modified: metadata.modified().ok(),
+		tag_hash: tag.tag_hash(),
+	})
+}
+
+/// Fingerprint a file that was just written, reading its tag back
+pub fn fingerprint_written(path: &Path) -> Result<Fingerprint, Error> {
+	fingerprint(path, &files::get_metadata(path)?)
+}
+
+/// Whether the file's tag differs from the fingerprint. The tag is read even
+/// if the size and modification time are the same, since tools can edit a
+/// tag in its padding and keep the modification time. Changes to the audio
+/// don't count, since saving copies the audio from disk.
+pub fn is_changed(path: &Path, recorded: &Fingerprint) -> bool {
+	match files::get_metadata(path) {
+		Ok(metadata) => metadata.tag_hash() != recorded.tag_hash,
+		// deleted, moved or not readable anymore
+		Err(_) => true,
+	}
+}
+
+#[derive(Default)]
+struct WatcherInner {
+	watcher: Option<RecommendedWatcher>,
+	dirs: HashSet<PathBuf>,
+}
+
+/// Watches the folders of open files. Folders are watched instead of files,
+/// since saving replaces the file.
+#[derive(Default)]
+pub struct FileWatcher(Mutex<WatcherInner>);
+
+pub fn start(app_handle: &AppHandle) {
+	let handle = app_handle.clone();
+	let watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+		if let Ok(event) = result {
+			if !matches!(event.kind, EventKind::Access(_)) {
+				handle_event(&handle, &event.paths);
+			}
+		}
+	});
+	let state = app_handle.state::<FileWatcher>();
+	if let (Ok(watcher), Ok(mut inner)) = (watcher, state.0.lock()) {
+		inner.watcher = Some(watcher);
+	}
+}
+
+fn handle_event(app_handle: &AppHandle, paths: &[PathBuf]) {
+	let state = app_handle.state::<AppState>();
+	for path in paths {
+		let recorded = match state.lock() {
+			Ok(app) => app
+				.files
+				.iter()
+				.find(|file| &file.path == path && !file.changed_on_disk)
+				.and_then(|file| file.disk.clone()),
+			Err(_) => return,
+		};
+		let recorded = match recorded {
+			Some(recorded) => recorded,
+			None => continue,
+		};
+		// read the tag without locking the app
+		if !is_changed(path, &recorded) {
+			continue;
+		}
+		if let Ok(mut app) = state.lock() {
+			// skip if the file was saved or reloaded in the meantime
+			let file = app
+				.files
+				.iter_mut()
+				.find(|file| &file.path == path && file.disk.as_ref() == Some(&recorded));
+			if let Some(file) = file {
+				file.changed_on_disk = true;
+				let _ = app_handle.emit_all("file_changed", path);
+			}
+		}
+	}
+}
+
+/// Watch the folders of the open files, and stop watching other folders
+pub fn watch_files(watcher: &FileWatcher, files: &[File]) {
+	let mut inner = match watcher.0.lock() {
+		Ok(inner) => inner,
+		Err(_) => return,
+	};
+	let dirs: HashSet<PathBuf> = files
+		.iter()
+		.filter_map(|file| file.path.parent())
+		.map(|dir| dir.to_path_buf())
+		.collect();
+	let WatcherInner {
+		watcher,
+		dirs: watched,
+	} = &mut *inner;
+	let watcher = match watcher {
+		Some(watcher) => watcher,
+		None => return,
+	};
+	for dir in watched.difference(&dirs) {
+		let _ = watcher.unwatch(dir);
+	}
+	for dir in dirs.difference(watched) {
+		let _ = watcher.watch(dir, RecursiveMode::NonRecursive);
+	}
+	*watched = dirs;
+}
+
+#[derive(Serialize)]
+pub struct ThreeWayChange {
+	field: String,
+	/// When the file was opened or last saved
+	base: String,
+	/// Unsaved changes in the app
+	ours: String,
+	/// Now on disk
+	theirs: String,
+}
+
+fn value<'a>(row: &'a Row, column: &str) -> &'a str {
+	match row.iter().find(|(c, _)| c == column) {
+		Some((_, value)) => value,
+		None => "",
+	}
+}
+
+/// If the file was changed by another program since it was opened or saved,
+/// list the fields that differ between the version it was opened as, the
+/// app and the disk
+#[command]
+pub fn get_external_changes(
+	index: usize,
+	app: AppArg<'_>,
+) -> Result<Option<Vec<ThreeWayChange>>, Error> {
+	let mut app = app.lock()?;
+	let popm_email = app.popm_email.clone();
+	let file = app.file_mut(index)?;
+	let changed = match &file.disk {
+		Some(disk) => is_changed(&file.path, disk),
+		None => false,
+	};
+	if !changed {
+		return Ok(None);
+	}
+	file.changed_on_disk = true;
+	let base = spreadsheet::metadata_to_row(&file.saved_metadata, &popm_email);
+	let ours = spreadsheet::metadata_to_row(&file.metadata, &popm_email);
+	let theirs = match files::get_metadata(&file.path) {
+		Ok(metadata) => spreadsheet::metadata_to_row(&metadata, &popm_email),
+		// deleted, or not readable anymore
+		Err(_) => Row::new(),
+	};
+	let mut fields: Vec<&str> = Vec::new();
+	for (column, _) in base.iter().chain(&ours).chain(&theirs) {
+		if !fields.contains(&column.as_str()) {
+			fields.push(column);
+		}
+	}
+	let changes = fields
+		.into_iter()
+		.filter_map(|field| {
+			let (base, ours, theirs) = (
+				value(&base, field),
+				value(&ours, field),
+				value(&theirs, field),
+			);
+			if base == ours && base == theirs {
+				return None;
+			}
+			Some(ThreeWayChange {
+				field: field.to_string(),
+				base: base.to_string(),
+				ours: ours.to_string(),
+				theirs: theirs.to_string(),
+			})
+		})
+		.collect();
+	Ok(Some(changes))
+}
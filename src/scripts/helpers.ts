@@ -5,8 +5,51 @@ export function popup(msg: string) {
 	invoke('error_popup', { msg })
 }
 
+export type ErrorKind =
+	| 'Io'
+	| 'UnsupportedFormat'
+	| 'Parse'
+	| 'IndexOutOfRange'
+	| 'Cancelled'
+	| 'Conflict'
+	| 'InvalidInput'
+	| 'Poisoned'
+	| 'Other'
+export type AppError = {
+	kind: ErrorKind
+	message: string
+	path: string | null
+}
+
+export function isAppError(error: unknown): error is AppError {
+	return typeof error === 'object' && error !== null && 'kind' in error && 'message' in error
+}
+
+export function errorPopup(error: unknown) {
+	if (!isAppError(error)) {
+		popup(String(error))
+	} else if (error.kind !== 'Cancelled') {
+		popup(error.path ? `${error.message}\n\n${error.path}` : error.message)
+	}
+}
+
 export async function runCmd<T = unknown>(cmd: string, options: { [key: string]: unknown } = {}) {
-	return (await invoke(cmd, options).catch(popup)) as T
+	return (await invoke(cmd, options).catch(errorPopup)) as T
+}
+
+const isWindows = navigator.userAgent.includes('Windows')
+
+/** URL of an image in an open file, served by the `art` protocol */
+export function artUrl(
+	fileIndex: number,
+	imageIndex: number | 'default',
+	options: { thumb?: number; version?: string | number } = {},
+) {
+	const base = isWindows ? 'https://art.localhost/' : 'art://localhost/'
+	const params = new URLSearchParams()
+	if (options.thumb) params.set('thumb', options.thumb.toString())
+	if (options.version !== undefined) params.set('v', options.version.toString())
+	return `${base}${fileIndex}/${imageIndex}?${params}`
 }
 
 export function extractUnlistener(futureUnlistener: Promise<event.UnlistenFn>) {